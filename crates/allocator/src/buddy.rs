@@ -3,6 +3,7 @@ use core::cmp::max;
 use core::mem::size_of;
 use core::ptr::NonNull;
 
+use crate::AllocError;
 use crate::list;
 
 pub struct Heap<const ORDER: usize> {
@@ -21,6 +22,13 @@ impl<const ORDER: usize> Heap<ORDER> {
         }
     }
 
+    /// Add the memory `[start, end)` to the heap, trimmed to word alignment.
+    ///
+    /// # Safety
+    ///
+    /// The range must be valid for reads and writes, used by nothing else
+    /// and not already in the heap. It belongs to the heap until taken out
+    /// by `remove_free`.
     pub unsafe fn add_range(&mut self, mut start: usize, mut end: usize) {
         // align start and end
         start = (start + size_of::<usize>() - 1) & (!size_of::<usize>() + 1);
@@ -39,11 +47,16 @@ impl<const ORDER: usize> Heap<ORDER> {
         self.total += total;
     }
 
+    /// Add the memory `[start, start + size)` to the heap.
+    ///
+    /// # Safety
+    ///
+    /// Same as `add_range`.
     pub unsafe fn add_size(&mut self, start: usize, size: usize) {
         unsafe { self.add_range(start, start + size) };
     }
 
-    pub fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+    pub fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
         let size = max(
             layout.size().next_power_of_two(),
             max(layout.align(), size_of::<usize>()),
@@ -60,7 +73,7 @@ impl<const ORDER: usize> Heap<ORDER> {
                             self.free_area[j - 1].push(block);
                         }
                     } else {
-                        return Err(());
+                        return Err(AllocError);
                     }
                 }
                 let result = NonNull::new(
@@ -72,11 +85,11 @@ impl<const ORDER: usize> Heap<ORDER> {
                     self.allocated += size;
                     return Ok(result);
                 } else {
-                    return Err(());
+                    return Err(AllocError);
                 }
             }
         }
-        Err(())
+        Err(AllocError)
     }
 
     pub fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
@@ -115,6 +128,40 @@ impl<const ORDER: usize> Heap<ORDER> {
         self.allocated -= size;
     }
 
+    /// Take the free memory `[start, start + size)` out of the heap, splitting
+    /// the free block containing it. `size` must be a power of two and `start`
+    /// aligned to it. Returns false if the range is not entirely free.
+    pub fn remove_free(&mut self, start: usize, size: usize) -> bool {
+        debug_assert!(size.is_power_of_two() && start.is_multiple_of(size));
+        let order = size.trailing_zeros() as usize;
+        for i in order..ORDER {
+            let block = start & !((1 << i) - 1);
+            let Some(node) = self.free_area[i]
+                .iter_mut()
+                .find(|node| node.value() as usize == block)
+            else {
+                continue;
+            };
+            node.pop();
+            // Give back the halves which do not contain the range
+            let mut block = block;
+            for j in (order..i).rev() {
+                let half = 1 << j;
+                unsafe {
+                    if start & half != 0 {
+                        self.free_area[j].push(block as *mut usize);
+                        block += half;
+                    } else {
+                        self.free_area[j].push((block + half) as *mut usize);
+                    }
+                }
+            }
+            self.total -= size;
+            return true;
+        }
+        false
+    }
+
     pub fn total(&self) -> usize {
         self.total
     }
@@ -3,15 +3,27 @@ extern crate alloc;
 
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ops::Deref;
+use core::ptr::NonNull;
 use sync::SpinNoIrqMutex;
 
 mod buddy;
 mod list;
+mod slab;
 
-use buddy::Heap;
+pub use buddy::Heap;
+pub use slab::{MAX_CACHES, MAX_OBJECT_SIZE, SlabAllocator};
+
+/// The heap has no free block large enough for an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+/// Called with the heap locked when an allocation fails, it may add memory
+/// to the heap before the allocation is retried. It must not allocate.
+pub type Rescue<const ORDER: usize> = fn(&mut Heap<ORDER>, &Layout);
 
 pub struct LockedAllocator<const ORDER: usize> {
     heap: SpinNoIrqMutex<Heap<ORDER>>,
+    rescue: Option<Rescue<ORDER>>,
 }
 
 impl<const ORDER: usize> Default for LockedAllocator<ORDER> {
@@ -24,8 +36,27 @@ impl<const ORDER: usize> LockedAllocator<ORDER> {
     pub const fn new() -> Self {
         LockedAllocator {
             heap: SpinNoIrqMutex::new(Heap::new()),
+            rescue: None,
         }
     }
+
+    pub const fn with_rescue(rescue: Rescue<ORDER>) -> Self {
+        LockedAllocator {
+            heap: SpinNoIrqMutex::new(Heap::new()),
+            rescue: Some(rescue),
+        }
+    }
+
+    /// Allocate from the buddy heap, calling the rescue function once if it
+    /// is out of memory.
+    pub fn alloc_block(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let mut heap = self.heap.lock();
+        heap.alloc(layout).or_else(|_| {
+            let rescue = self.rescue.ok_or(AllocError)?;
+            rescue(&mut heap, &layout);
+            heap.alloc(layout)
+        })
+    }
 }
 
 impl<const ORDER: usize> Deref for LockedAllocator<ORDER> {
@@ -38,15 +69,13 @@ impl<const ORDER: usize> Deref for LockedAllocator<ORDER> {
 
 unsafe impl<const ORDER: usize> GlobalAlloc for LockedAllocator<ORDER> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.heap
-            .lock()
-            .alloc(layout)
+        self.alloc_block(layout)
             .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         self.heap
             .lock()
-            .dealloc(unsafe { core::ptr::NonNull::new_unchecked(ptr) }, layout)
+            .dealloc(unsafe { NonNull::new_unchecked(ptr) }, layout)
     }
 }
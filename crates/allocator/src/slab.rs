@@ -0,0 +1,290 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use sync::SpinNoIrqMutex;
+
+use crate::buddy::Heap;
+use crate::list::List;
+use crate::{AllocError, LockedAllocator, Rescue};
+
+/// Maximum number of object caches, including the default size classes
+pub const MAX_CACHES: usize = 32;
+
+/// Objects larger than this come straight from the buddy allocator
+pub const MAX_OBJECT_SIZE: usize = 2048;
+
+// Size classes between the powers of two keep the waste of rounding up
+// below a third
+const DEFAULT_SIZES: [usize; 15] = [
+    8, 16, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 1536, 2048,
+];
+
+const SLAB_SIZE: usize = 4096;
+const MIN_SLAB_OBJECTS: usize = 8;
+
+/// Empty slabs go back to the buddy allocator while the depot holds more
+/// than this many slabs worth of free objects
+const DEPOT_SLABS: usize = 2;
+
+// States of the cache table
+const OPEN: u8 = 0;
+const ADDING: u8 = 1;
+const SEALED: u8 = 2;
+
+/// Objects a hart keeps for itself per cache, half of them are exchanged
+/// with the shared depot at once
+const MAGAZINE_SIZE: usize = 16;
+
+/// A stack of free objects
+struct FreeList {
+    list: List,
+    len: usize,
+}
+
+impl FreeList {
+    const fn new() -> Self {
+        FreeList {
+            list: List::new(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, obj: *mut usize) {
+        unsafe { self.list.push(obj) };
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut usize> {
+        let obj = self.list.pop()?;
+        self.len -= 1;
+        Some(obj)
+    }
+}
+
+/// Size of the slabs of objects of `size` bytes, they are aligned to it
+fn slab_size(size: usize) -> usize {
+    (size * MIN_SLAB_OBJECTS + size_of::<usize>())
+        .next_power_of_two()
+        .max(SLAB_SIZE)
+}
+
+/// Number of objects of `size` bytes in a slab. The last word of the slab
+/// counts how many of them are in the depot.
+fn slab_objects(size: usize) -> usize {
+    (slab_size(size) - size_of::<usize>()) / size
+}
+
+/// The depot count of the slab holding `obj`
+fn depot_count(size: usize, obj: *mut usize) -> *mut usize {
+    let slab_size = slab_size(size);
+    let slab = obj as usize & !(slab_size - 1);
+    (slab + slab_size - size_of::<usize>()) as *mut usize
+}
+
+/// Caches fixed-size objects carved out of slabs from the buddy allocator.
+///
+/// Every cache has a depot shared by all harts and a magazine per hart, so
+/// most allocations only take the lock of the local magazine. A slab whose
+/// objects are all in the depot goes back to the buddy allocator once the
+/// depot holds more than `DEPOT_SLABS` slabs worth of objects.
+pub struct SlabAllocator<const ORDER: usize, const HARTS: usize> {
+    heap: LockedAllocator<ORDER>,
+    hart_id: fn() -> usize,
+    // Object sizes of the caches in ascending order
+    sizes: [AtomicUsize; MAX_CACHES],
+    caches: AtomicUsize,
+    // `ADDING` while `add_cache` runs, `SEALED` from the first allocation on
+    state: AtomicU8,
+    depots: [SpinNoIrqMutex<FreeList>; MAX_CACHES],
+    magazines: [[SpinNoIrqMutex<FreeList>; MAX_CACHES]; HARTS],
+}
+
+impl<const ORDER: usize, const HARTS: usize> SlabAllocator<ORDER, HARTS> {
+    /// `hart_id` returns the index of the current hart
+    pub const fn new(hart_id: fn() -> usize) -> Self {
+        Self::with_heap(LockedAllocator::new(), hart_id)
+    }
+
+    /// Like `new`, with a buddy heap which calls `rescue` when it runs out
+    pub const fn with_rescue(hart_id: fn() -> usize, rescue: Rescue<ORDER>) -> Self {
+        Self::with_heap(LockedAllocator::with_rescue(rescue), hart_id)
+    }
+
+    const fn with_heap(heap: LockedAllocator<ORDER>, hart_id: fn() -> usize) -> Self {
+        let mut sizes = [const { AtomicUsize::new(0) }; MAX_CACHES];
+        let mut i = 0;
+        while i < DEFAULT_SIZES.len() {
+            sizes[i] = AtomicUsize::new(DEFAULT_SIZES[i]);
+            i += 1;
+        }
+        SlabAllocator {
+            heap,
+            hart_id,
+            sizes,
+            caches: AtomicUsize::new(DEFAULT_SIZES.len()),
+            state: AtomicU8::new(OPEN),
+            depots: [const { SpinNoIrqMutex::new(FreeList::new()) }; MAX_CACHES],
+            magazines: [const { [const { SpinNoIrqMutex::new(FreeList::new()) }; MAX_CACHES] };
+                HARTS],
+        }
+    }
+
+    /// Add a cache for objects of exactly `size` bytes, so a frequently
+    /// allocated type does not waste the rest of its size class. Must be
+    /// called before the first allocation, and not on several harts at once.
+    pub fn add_cache(&self, size: usize) {
+        match self
+            .state
+            .compare_exchange(OPEN, ADDING, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => {}
+            Err(SEALED) => panic!("slab caches must be added before the first allocation"),
+            Err(_) => panic!("slab caches added concurrently"),
+        }
+        self.insert_size(size.next_multiple_of(size_of::<usize>()));
+        self.state.store(OPEN, Ordering::Release);
+    }
+
+    fn insert_size(&self, size: usize) {
+        let caches = self.caches.load(Ordering::Relaxed);
+        if size > MAX_OBJECT_SIZE || (0..caches).any(|i| self.size(i) == size) {
+            return;
+        }
+        assert!(caches < MAX_CACHES, "too many slab caches");
+        let mut i = caches;
+        while i > 0 && self.size(i - 1) > size {
+            self.sizes[i].store(self.size(i - 1), Ordering::Relaxed);
+            i -= 1;
+        }
+        self.sizes[i].store(size, Ordering::Relaxed);
+        self.caches.store(caches + 1, Ordering::Release);
+    }
+
+    fn size(&self, cache: usize) -> usize {
+        self.sizes[cache].load(Ordering::Relaxed)
+    }
+
+    /// The smallest cache whose objects fit `layout`
+    fn cache_of(&self, layout: Layout) -> Option<usize> {
+        if layout.size() > MAX_OBJECT_SIZE {
+            return None;
+        }
+        // Objects are laid out back to back from the start of the slab, so
+        // they are aligned to the lowest set bit of their size
+        (0..self.caches.load(Ordering::Acquire)).find(|&i| {
+            let size = self.size(i);
+            size >= layout.size() && 1 << size.trailing_zeros() >= layout.align()
+        })
+    }
+
+    fn magazine(&self, cache: usize) -> &SpinNoIrqMutex<FreeList> {
+        &self.magazines[(self.hart_id)() % HARTS][cache]
+    }
+
+    fn slab_layout(&self, cache: usize) -> Layout {
+        let slab_size = slab_size(self.size(cache));
+        Layout::from_size_align(slab_size, slab_size).unwrap()
+    }
+
+    /// Fill `magazine` from the depot, carving a new slab if it is empty
+    fn refill(&self, cache: usize, magazine: &mut FreeList) -> Result<(), AllocError> {
+        let size = self.size(cache);
+        let mut depot = self.depots[cache].lock();
+        if depot.len == 0 {
+            let slab = self.heap.alloc_block(self.slab_layout(cache))?.as_ptr() as usize;
+            let objects = slab_objects(size);
+            for i in (0..objects).rev() {
+                unsafe { depot.push((slab + i * size) as *mut usize) };
+            }
+            unsafe { *depot_count(size, slab as *mut usize) = objects };
+        }
+        for _ in 0..MAGAZINE_SIZE / 2 {
+            let Some(obj) = depot.pop() else {
+                break;
+            };
+            unsafe {
+                *depot_count(size, obj) -= 1;
+                magazine.push(obj);
+            }
+        }
+        Ok(())
+    }
+
+    /// Move half of the full `magazine` to the depot, giving back the slabs
+    /// which become empty while the depot has plenty of objects
+    fn flush(&self, cache: usize, magazine: &mut FreeList) {
+        let size = self.size(cache);
+        let objects = slab_objects(size);
+        let mut depot = self.depots[cache].lock();
+        for obj in core::iter::from_fn(|| magazine.pop()).take(MAGAZINE_SIZE / 2) {
+            let count = depot_count(size, obj);
+            unsafe {
+                depot.push(obj);
+                *count += 1;
+            }
+            if unsafe { *count } == objects && depot.len > DEPOT_SLABS * objects {
+                self.release(cache, &mut depot, obj);
+            }
+        }
+    }
+
+    /// Take the objects of the empty slab holding `obj` out of `depot` and
+    /// give the slab back to the buddy allocator
+    fn release(&self, cache: usize, depot: &mut FreeList, obj: *mut usize) {
+        let layout = self.slab_layout(cache);
+        let slab = obj as usize & !(layout.size() - 1);
+        let mut kept = FreeList::new();
+        while let Some(obj) = depot.pop() {
+            if !(slab..slab + layout.size()).contains(&(obj as usize)) {
+                unsafe { kept.push(obj) };
+            }
+        }
+        *depot = kept;
+        unsafe { self.heap.dealloc(slab as *mut u8, layout) };
+    }
+
+    /// Number of bytes held by the buddy allocator, including slabs
+    pub fn allocated(&self) -> usize {
+        self.heap.lock().allocated()
+    }
+}
+
+impl<const ORDER: usize, const HARTS: usize> Deref for SlabAllocator<ORDER, HARTS> {
+    type Target = SpinNoIrqMutex<Heap<ORDER>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.heap
+    }
+}
+
+unsafe impl<const ORDER: usize, const HARTS: usize> GlobalAlloc for SlabAllocator<ORDER, HARTS> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.state.load(Ordering::Relaxed) != SEALED {
+            assert!(
+                self.state.swap(SEALED, Ordering::AcqRel) != ADDING,
+                "allocation while adding a slab cache"
+            );
+        }
+        let Some(cache) = self.cache_of(layout) else {
+            return unsafe { self.heap.alloc(layout) };
+        };
+        let mut magazine = self.magazine(cache).lock();
+        if magazine.len == 0 && self.refill(cache, &mut magazine).is_err() {
+            return ptr::null_mut();
+        }
+        magazine.pop().map_or(ptr::null_mut(), |obj| obj as *mut u8)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(cache) = self.cache_of(layout) else {
+            return unsafe { self.heap.dealloc(ptr, layout) };
+        };
+        let mut magazine = self.magazine(cache).lock();
+        if magazine.len == MAGAZINE_SIZE {
+            self.flush(cache, &mut magazine);
+        }
+        unsafe { magazine.push(ptr as *mut usize) };
+    }
+}
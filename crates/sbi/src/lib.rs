@@ -7,9 +7,23 @@ pub mod dbcn;
 pub mod hsm;
 pub mod legacy;
 pub mod reset;
+pub mod rfence;
 
 #[inline(always)]
 pub fn sbi_call(eid: u64, fid: u64, arg0: u64, arg1: u64, arg2: u64) -> Sbiret {
+    sbi_call_5(eid, fid, arg0, arg1, arg2, 0, 0)
+}
+
+#[inline(always)]
+pub fn sbi_call_5(
+    eid: u64,
+    fid: u64,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+) -> Sbiret {
     let error: i64;
     let value: u64;
     unsafe {
@@ -20,6 +34,8 @@ pub fn sbi_call(eid: u64, fid: u64, arg0: u64, arg1: u64, arg2: u64) -> Sbiret {
             in("x10") arg0,
             in("x11") arg1,
             in("x12") arg2,
+            in("x13") arg3,
+            in("x14") arg4,
             lateout("x10") error,
             lateout("x11") value,
         };
@@ -0,0 +1,46 @@
+use crate::{Sbiret, sbi_call, sbi_call_5};
+
+const EID_RFENCE: u64 = 0x52464E43;
+
+const FID_REMOTE_FENCE_I: u64 = 0;
+const FID_REMOTE_SFENCE_VMA: u64 = 1;
+const FID_REMOTE_SFENCE_VMA_ASID: u64 = 2;
+
+pub fn sbi_remote_fence_i(hart_mask: u64, hart_mask_base: u64) -> Sbiret {
+    sbi_call(EID_RFENCE, FID_REMOTE_FENCE_I, hart_mask, hart_mask_base, 0)
+}
+
+pub fn sbi_remote_sfence_vma(
+    hart_mask: u64,
+    hart_mask_base: u64,
+    start_addr: u64,
+    size: u64,
+) -> Sbiret {
+    sbi_call_5(
+        EID_RFENCE,
+        FID_REMOTE_SFENCE_VMA,
+        hart_mask,
+        hart_mask_base,
+        start_addr,
+        size,
+        0,
+    )
+}
+
+pub fn sbi_remote_sfence_vma_asid(
+    hart_mask: u64,
+    hart_mask_base: u64,
+    start_addr: u64,
+    size: u64,
+    asid: u64,
+) -> Sbiret {
+    sbi_call_5(
+        EID_RFENCE,
+        FID_REMOTE_SFENCE_VMA_ASID,
+        hart_mask,
+        hart_mask_base,
+        start_addr,
+        size,
+        asid,
+    )
+}
@@ -0,0 +1,51 @@
+//! What each hart is in the middle of, so that code abandoning a call chain
+//! can tell whether it would leave a lock held or interrupts disabled.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Harts whose counts are kept apart, the kernel must have no more
+pub const MAX_HARTS: usize = 16;
+
+struct Depth {
+    locks: AtomicUsize,
+    irqs_off: AtomicUsize,
+}
+
+static DEPTH: [Depth; MAX_HARTS] = [const {
+    Depth {
+        locks: AtomicUsize::new(0),
+        irqs_off: AtomicUsize::new(0),
+    }
+}; MAX_HARTS];
+
+// Only the hart itself updates its counts. User space keeps anything in
+// `tp`, its counts are never read.
+fn depth() -> &'static Depth {
+    &DEPTH[arch::get_hart_id() % MAX_HARTS]
+}
+
+/// Number of locks the current hart holds
+pub fn locks_held() -> usize {
+    depth().locks.load(Ordering::Relaxed)
+}
+
+/// Nesting of the sections the current hart runs with interrupts disabled
+pub fn irqs_off() -> usize {
+    depth().irqs_off.load(Ordering::Relaxed)
+}
+
+pub(crate) fn lock_acquired() {
+    depth().locks.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn lock_released() {
+    depth().locks.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub(crate) fn irqs_disabled() {
+    depth().irqs_off.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn irqs_restored() {
+    depth().irqs_off.fetch_sub(1, Ordering::Relaxed);
+}
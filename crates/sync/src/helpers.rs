@@ -1,4 +1,4 @@
-use crate::MutexHelper;
+use crate::{MutexHelper, depth};
 
 pub struct SpinHelper {}
 impl MutexHelper for SpinHelper {
@@ -20,9 +20,11 @@ impl MutexHelper for SpinNoIrqHelper {
     fn before_lock() -> bool {
         let sie = arch::read_sie();
         arch::disable_sie();
+        depth::irqs_disabled();
         sie
     }
     fn after_lock(sie: &bool) {
+        depth::irqs_restored();
         if *sie {
             arch::enable_sie();
         }
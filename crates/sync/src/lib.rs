@@ -1,5 +1,6 @@
 #![no_std]
 
+pub mod depth;
 mod helpers;
 mod lazy;
 pub mod mutex;
@@ -6,7 +6,7 @@ use core::{
     sync::atomic::{AtomicI32, Ordering},
 };
 
-use crate::MutexHelper;
+use crate::{MutexHelper, depth};
 
 pub struct Mutex<T: ?Sized, H: MutexHelper> {
     _helper: PhantomData<H>,
@@ -65,6 +65,7 @@ impl<T: ?Sized, H: MutexHelper> Mutex<T, H> {
                 }
             }
         }
+        depth::lock_acquired();
         MutexGuard {
             mutex: self,
             helper_data,
@@ -79,6 +80,7 @@ impl<T: ?Sized, H: MutexHelper> Mutex<T, H> {
             .compare_exchange(0, (hartid << 1) | 1, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
+            depth::lock_acquired();
             Some(MutexGuard {
                 mutex: self,
                 helper_data,
@@ -98,6 +100,7 @@ pub struct MutexGuard<'a, T: ?Sized + 'a, H: MutexHelper + 'a> {
 impl<T: ?Sized, H: MutexHelper> Drop for MutexGuard<'_, T, H> {
     fn drop(&mut self) {
         self.mutex.state.store(0, Ordering::Release);
+        depth::lock_released();
         H::after_lock(&self.helper_data);
     }
 }
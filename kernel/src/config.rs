@@ -1,16 +1,36 @@
 pub const CPU_NUM: usize = 4;
 
-pub const KERNEL_HEAP_SIZE: usize = 0x100_0000; // 16MiB
+pub const KERNEL_HEAP_SIZE: usize = 0x40_0000; // 4MiB, the heap grows from frames beyond that
 
-pub const TASK_STACK_SIZE: usize = 0x8000; // 32KiB
+pub const KERNEL_HEAP_MAX_GROWTH: usize = 0x4000_0000; // 1GiB of virtual space to grow into
+
+pub const KERNEL_HEAP_SHRINK: bool = true; // Give fully free growth chunks back to the frame allocator
+
+pub const KERNEL_HEAP_SHRINK_INTERVAL: usize = 1000; // Milliseconds between heap shrinks by idle harts
+
+pub const KERNEL_STACK_SIZE: usize = 0x10_0000; // 1MiB per hart, with an unmapped guard of the same size below
+
+pub const TASK_STACK_SIZE: usize = 0x80_0000; // 8MiB, default limit of the growable user stack
+
+pub const USER_ARG_MAX: usize = 0x2_0000; // 128KiB, room for arguments, environment and auxv on the initial stack
+
+pub const USER_ASLR: bool = true; // Randomize PIE load bases, stack tops and mmap bases
+
+pub const USER_STACK_RANDOM: usize = 0x100_0000; // 16MiB, range the stack top is moved down by
+
+pub const USER_MMAP_RANDOM: usize = 0x400_0000; // 64MiB, range the mmap base is moved up by
 
 pub const MAX_TASKS: usize = 1024;
 
+pub const MAX_OPEN_FILES: usize = 32; // Per task
+
+pub const SWAP_SIZE: usize = 0x400_0000; // 64MiB, most of the swap partition used
+
+pub const SWAP_CLUSTER: usize = 32; // Pages reclaimed at once when out of memory
+
 // -- From device tree
 
 pub static mut MEMORY_SIZE: usize = 0;
 
 pub static mut UART_BASE: usize = 0;
-
-pub static mut PLIC_BASE: usize = 0;
 // TODO Add device memory region
@@ -1,17 +1,23 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc};
 use core::{
     fmt::{self, Write},
     sync::atomic::{AtomicBool, Ordering},
 };
+use fdt::Fdt;
+use log::{info, warn};
 use sync::Lazy;
 
 use crate::{
     Mutex,
-    drivers::serial::ConsoleDevice,
+    drivers::{plic, serial::ConsoleDevice},
     mm::address_space::{K_HARDWARE_BEG, KERNEL_OFFSET},
+    task::{taskdef::TaskControlBlock, wait_queue::WaitQueue},
+    utils::ring_buffer::RingBuffer,
 };
 use crate::{config::UART_BASE, drivers::serial::Uart};
 
+const INPUT_BUFFER_SIZE: usize = 256;
+
 static PRINT_LOCK: Mutex<()> = Mutex::new(());
 
 pub static CONSOLE: Lazy<Box<dyn ConsoleDevice + Send + Sync>> = Lazy::new(|| {
@@ -48,8 +54,49 @@ pub fn _print(args: fmt::Arguments<'_>) {
     Stdout.write_fmt(args).unwrap();
 }
 
-pub fn getchar() -> u8 {
-    CONSOLE.getc()
+/// Bytes received by the UART, filled by its interrupt handler
+static INPUT: Lazy<RingBuffer<u8, INPUT_BUFFER_SIZE>> = Lazy::new(RingBuffer::new);
+static INPUT_WAITERS: WaitQueue = WaitQueue::new();
+static INPUT_IRQ: AtomicBool = AtomicBool::new(false);
+
+fn uart_interrupt() {
+    while let Some(ch) = CONSOLE.try_getc() {
+        // Input is dropped while nobody reads it and the buffer is full
+        let _ = INPUT.push(ch);
+    }
+    INPUT_WAITERS.wake_all();
+}
+
+/// Take console input through the UART interrupt instead of polling
+pub fn init_input(fdt: &Fdt) {
+    let irq = fdt
+        .find_compatible(&["ns16550a"])
+        .and_then(|node| plic::irq_of(&node));
+    match irq.map(|irq| (irq, plic::register(irq, uart_interrupt))) {
+        Some((irq, Ok(()))) => {
+            INPUT_IRQ.store(true, Ordering::Release);
+            info!("Console input on IRQ {}.", irq);
+        }
+        _ => warn!("No UART interrupt, console input is polled."),
+    }
+}
+
+/// Take a received byte. If there is none, `task` is put to sleep until one
+/// arrives, or just yields if input is polled.
+pub fn getchar_or_wait(task: &Arc<TaskControlBlock>) -> Option<u8> {
+    if !INPUT_IRQ.load(Ordering::Acquire) {
+        let ch = CONSOLE.try_getc();
+        if ch.is_none() {
+            task.set_yield_flag(true);
+        }
+        return ch;
+    }
+    let mut ch = None;
+    INPUT_WAITERS.wait_unless(task, || {
+        ch = INPUT.pop();
+        ch.is_some()
+    });
+    ch
 }
 
 #[macro_export]
@@ -1,17 +1,19 @@
+use core::ops::Range;
+
 use fdt::Fdt;
 
-use crate::config;
+use crate::{config, mm::address_space::PHYSICAL_MEMORY_START};
 
 pub fn parse_fdt(dtb: usize) -> Fdt<'static> {
     let device_tree = unsafe { fdt::Fdt::from_ptr(dtb as _) }
         .unwrap_or_else(|e| panic!("Failed to parse device tree: {:?}", e));
-    let physical_memory = device_tree
-        .memory()
-        .regions()
-        .next()
+    // Memory is linearly mapped up to the end of the highest region
+    let memory_end = memory_regions(&device_tree)
+        .map(|region| region.end)
+        .max()
         .expect("No memory region in device tree");
     unsafe {
-        config::MEMORY_SIZE = physical_memory.size.unwrap();
+        config::MEMORY_SIZE = memory_end - PHYSICAL_MEMORY_START;
     }
 
     // TODO: Refactor this
@@ -19,11 +21,24 @@ pub fn parse_fdt(dtb: usize) -> Fdt<'static> {
     unsafe {
         config::UART_BASE = stdout.reg().unwrap().next().unwrap().starting_address as usize;
     }
+    device_tree
+}
 
-    // PLIC
-    let plic = device_tree.find_compatible(&["riscv,plic0"]).unwrap();
-    unsafe {
-        config::PLIC_BASE = plic.reg().unwrap().next().unwrap().starting_address as usize;
-    }
+/// Physical memory of every memory node, regions below `PHYSICAL_MEMORY_START`
+/// are left out as they cannot be mapped linearly.
+pub fn memory_regions<'b>(
+    device_tree: &'b Fdt<'static>,
+) -> impl Iterator<Item = Range<usize>> + 'b {
     device_tree
+        .all_nodes()
+        .filter(|node| {
+            node.property("device_type").and_then(|prop| prop.as_str()) == Some("memory")
+        })
+        .filter_map(|node| node.reg())
+        .flatten()
+        .filter_map(|region| {
+            let start = region.starting_address as usize;
+            let end = start.checked_add(region.size?)?;
+            (end > PHYSICAL_MEMORY_START).then(|| start.max(PHYSICAL_MEMORY_START)..end)
+        })
 }
@@ -0,0 +1,113 @@
+mod virtio_blk;
+
+use alloc::{boxed::Box, vec::Vec};
+use fdt::Fdt;
+
+pub use virtio_blk::VirtioBlk;
+
+use crate::{
+    Mutex,
+    drivers::virtio::VirtioMmio,
+    error::OsError,
+    mm::{addr::PhysAddr, address_space::K_HARDWARE_BEG, frame, page::PageFlags},
+};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// MBR partition type of Linux swap
+pub const MBR_TYPE_SWAP: u8 = 0x82;
+
+static BLOCK_DEVICE: Mutex<Option<Box<dyn BlockDevice + Send>>> = Mutex::new(None);
+
+pub trait BlockDevice {
+    /// Number of `BLOCK_SIZE` blocks on the device
+    fn capacity(&self) -> usize;
+
+    /// Read `buf.len() / BLOCK_SIZE` blocks starting at `block`
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), OsError>;
+
+    /// Write `buf.len() / BLOCK_SIZE` blocks starting at `block`
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), OsError>;
+}
+
+/// Probe the block device, returns whether one was found.
+pub fn init(fdt: &Fdt) -> bool {
+    match probe(fdt) {
+        Some(dev) => {
+            *BLOCK_DEVICE.lock() = Some(Box::new(dev));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Run `f` on the block device, fails with `NoDisk` if there is none.
+pub fn with_device<R>(
+    f: impl FnOnce(&mut dyn BlockDevice) -> Result<R, OsError>,
+) -> Result<R, OsError> {
+    match BLOCK_DEVICE.lock().as_mut() {
+        Some(dev) => f(dev.as_mut()),
+        None => Err(OsError::NoDisk),
+    }
+}
+
+/// A primary partition in the MBR of the block device
+#[derive(Debug, Clone, Copy)]
+pub struct Partition {
+    /// Position in the partition table, from 1
+    pub number: usize,
+    pub ty: u8,
+    pub first_block: usize,
+    pub blocks: usize,
+}
+
+/// The primary partitions of the block device, none if it has no MBR.
+/// Partitions are cut to the end of the device.
+pub fn partitions() -> Vec<Partition> {
+    let Ok(frame) = frame::alloc() else {
+        return Vec::new();
+    };
+    let mbr = unsafe { PhysAddr::from(frame.ppn).as_mut_page_slice() };
+    frame.page().clear_flags(PageFlags::ZEROED);
+    let Ok(capacity) = with_device(|dev| {
+        dev.read_blocks(0, &mut mbr[..BLOCK_SIZE])?;
+        Ok(dev.capacity())
+    }) else {
+        return Vec::new();
+    };
+    if mbr[510..512] != [0x55, 0xaa] {
+        return Vec::new();
+    }
+    mbr[446..510]
+        .as_chunks::<16>()
+        .0
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry[4] != 0)
+        .map(|(i, entry)| {
+            let first_block = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+            let blocks = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+            Partition {
+                number: i + 1,
+                ty: entry[4],
+                first_block,
+                blocks: blocks.min(capacity.saturating_sub(first_block)),
+            }
+        })
+        .collect()
+}
+
+/// Find the first virtio block device in the device tree and initialize it.
+fn probe(fdt: &Fdt) -> Option<VirtioBlk> {
+    fdt.all_nodes()
+        .filter(|node| {
+            node.compatible()
+                .is_some_and(|c| c.all().any(|s| s == "virtio,mmio"))
+        })
+        .filter_map(|node| node.reg()?.next())
+        .find_map(|reg| {
+            let base = K_HARDWARE_BEG + reg.starting_address as usize;
+            VirtioMmio::probe(base, crate::drivers::virtio::DEVICE_ID_BLOCK)
+        })
+        .and_then(|mmio| VirtioBlk::new(mmio).ok())
+}
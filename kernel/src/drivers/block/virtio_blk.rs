@@ -0,0 +1,104 @@
+use log::info;
+
+use crate::{
+    drivers::virtio::{Buffer, VirtioMmio},
+    error::OsError,
+    mm::{
+        addr::{PhysAddr, pa2kva},
+        frame::{self, FrameTracker},
+    },
+};
+
+use super::{BLOCK_SIZE, BlockDevice};
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+// Offset of `capacity` in the device configuration space
+const CONFIG_CAPACITY: usize = 0x00;
+
+#[repr(C)]
+struct RequestHeader {
+    ty: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// The status byte follows the header in the request frame
+const STATUS_OFFSET: usize = size_of::<RequestHeader>();
+
+pub struct VirtioBlk {
+    mmio: VirtioMmio,
+    capacity: usize,
+    // Header and status of the request in flight, they must be in the linear
+    // map for the device to find them, which kernel stacks are not
+    request: FrameTracker,
+}
+
+impl VirtioBlk {
+    pub fn new(mut mmio: VirtioMmio) -> Result<Self, OsError> {
+        mmio.init()?;
+        let capacity = mmio.read_config::<u64>(CONFIG_CAPACITY) as usize;
+        info!(
+            "Found virtio block device with {} KiB.",
+            capacity * BLOCK_SIZE / 1024
+        );
+        Ok(Self {
+            mmio,
+            capacity,
+            request: frame::alloc()?,
+        })
+    }
+
+    fn request(&mut self, ty: u32, block: usize, data: Buffer) -> Result<(), OsError> {
+        if !data.len.is_multiple_of(BLOCK_SIZE) || block + data.len / BLOCK_SIZE > self.capacity {
+            return Err(OsError::InvalidParam);
+        }
+        let header = PhysAddr::from(self.request.ppn);
+        let status = pa2kva(header + STATUS_OFFSET).as_mut_ptr::<u8>();
+        unsafe {
+            pa2kva(header)
+                .as_mut_ptr::<RequestHeader>()
+                .write(RequestHeader {
+                    ty,
+                    reserved: 0,
+                    sector: block as u64,
+                });
+            status.write_volatile(0xff);
+        }
+        self.mmio.transfer(&[
+            Buffer {
+                pa: header,
+                len: size_of::<RequestHeader>(),
+                writable: false,
+            },
+            data,
+            Buffer {
+                pa: header + STATUS_OFFSET,
+                len: 1,
+                writable: true,
+            },
+        ]);
+        if unsafe { status.read_volatile() } == VIRTIO_BLK_S_OK {
+            Ok(())
+        } else {
+            Err(OsError::NoDisk)
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), OsError> {
+        self.request(VIRTIO_BLK_T_IN, block, Buffer::from_mut_slice(buf))
+    }
+
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), OsError> {
+        self.request(VIRTIO_BLK_T_OUT, block, Buffer::from_slice(buf))
+    }
+}
@@ -1,5 +1,8 @@
 #![allow(unused)]
 
 // TODO: add driver abstraction
+pub mod block;
+pub mod plic;
 pub mod rtc;
 pub mod serial;
+pub mod virtio;
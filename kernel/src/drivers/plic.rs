@@ -0,0 +1,224 @@
+//! Platform-Level Interrupt Controller. Every hart claims and completes
+//! external interrupts through the context of its supervisor mode, found in
+//! the `interrupts-extended` property of the PLIC node.
+//! See the RISC-V PLIC specification 1.0.0.
+
+use alloc::collections::BTreeMap;
+use arch::tp;
+use fdt::{Fdt, node::FdtNode};
+use log::{info, warn};
+
+use crate::{Mutex, config::CPU_NUM, error::OsError, mm::address_space::K_HARDWARE_BEG};
+
+// Register offsets
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+/// Interrupt number of supervisor external interrupts on a hart
+const SUPERVISOR_EXTERNAL: u32 = 9;
+
+const COMPATIBLE: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+
+struct Plic {
+    base: usize,
+    /// Number of interrupt sources, valid interrupts are `1..=sources`
+    sources: usize,
+    /// Supervisor context of each hart
+    contexts: [Option<usize>; CPU_NUM],
+    handlers: BTreeMap<usize, fn()>,
+}
+
+static PLIC: Mutex<Option<Plic>> = Mutex::new(None);
+
+impl Plic {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { self.reg(offset).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { self.reg(offset).write_volatile(value) }
+    }
+
+    fn context(&self, hart: usize) -> Option<usize> {
+        self.contexts.get(hart).copied().flatten()
+    }
+
+    fn set_priority(&self, irq: usize, priority: u32) {
+        self.write(PRIORITY + irq * 4, priority);
+    }
+
+    fn set_enabled(&self, context: usize, irq: usize, enabled: bool) {
+        let offset = ENABLE + context * ENABLE_STRIDE + irq / 32 * 4;
+        let bits = self.read(offset);
+        let bit = 1 << (irq % 32);
+        self.write(offset, if enabled { bits | bit } else { bits & !bit });
+    }
+
+    fn set_threshold(&self, context: usize, threshold: u32) {
+        self.write(CONTEXT + context * CONTEXT_STRIDE + THRESHOLD, threshold);
+    }
+
+    fn claim(&self, context: usize) -> usize {
+        self.read(CONTEXT + context * CONTEXT_STRIDE + CLAIM) as usize
+    }
+
+    fn complete(&self, context: usize, irq: usize) {
+        self.write(CONTEXT + context * CONTEXT_STRIDE + CLAIM, irq as u32);
+    }
+}
+
+/// Hart id of the CPU whose interrupt controller has `phandle`
+fn hart_of_intc(fdt: &Fdt, phandle: u32) -> Option<usize> {
+    fdt.find_node("/cpus")?
+        .children()
+        .filter(|cpu| cpu.name.split('@').next() == Some("cpu"))
+        .find(|cpu| {
+            cpu.children().any(|intc| {
+                intc.property("phandle")
+                    .and_then(|p| p.as_usize())
+                    .is_some_and(|p| p == phandle as usize)
+            })
+        })
+        .and_then(|cpu| cpu.property("reg")?.as_usize())
+}
+
+/// Find the PLIC in the device tree and mask every interrupt. Returns
+/// whether one was found.
+pub fn init(fdt: &Fdt) -> bool {
+    let Some(node) = fdt.find_compatible(COMPATIBLE) else {
+        return false;
+    };
+    let Some(reg) = node.reg().and_then(|mut reg| reg.next()) else {
+        return false;
+    };
+    let sources = node
+        .property("riscv,ndev")
+        .and_then(|p| p.as_usize())
+        .unwrap_or(0);
+    let mut contexts = [None; CPU_NUM];
+    let mut cells = node
+        .property("interrupts-extended")
+        .map_or(&[][..], |p| p.value)
+        .as_chunks::<4>()
+        .0
+        .iter()
+        .map(|&cell| u32::from_be_bytes(cell));
+    // One context per pair of interrupt controller and interrupt number
+    let mut context = 0;
+    while let (Some(phandle), Some(irq)) = (cells.next(), cells.next()) {
+        if irq == SUPERVISOR_EXTERNAL {
+            match hart_of_intc(fdt, phandle) {
+                Some(hart) if hart < CPU_NUM => contexts[hart] = Some(context),
+                _ => {}
+            }
+        }
+        context += 1;
+    }
+    let plic = Plic {
+        base: K_HARDWARE_BEG + reg.starting_address as usize,
+        sources,
+        contexts,
+        handlers: BTreeMap::new(),
+    };
+    for irq in 1..=sources {
+        plic.set_priority(irq, 0);
+    }
+    for context in contexts.iter().flatten() {
+        for irq in 1..=sources {
+            plic.set_enabled(*context, irq, false);
+        }
+    }
+    info!(
+        "PLIC at {:#x} with {} sources, contexts: {:?}",
+        reg.starting_address as usize, sources, contexts
+    );
+    *PLIC.lock() = Some(plic);
+    true
+}
+
+/// Let the current hart take every enabled interrupt
+pub fn init_hart() {
+    if let Some(plic) = PLIC.lock().as_ref() {
+        match plic.context(tp()) {
+            Some(context) => plic.set_threshold(context, 0),
+            None => warn!("No PLIC context for hart {}", tp()),
+        }
+    }
+}
+
+/// Interrupt number of `node` if it is wired to the PLIC
+pub fn irq_of(node: &FdtNode) -> Option<usize> {
+    let parent = node.interrupt_parent()?;
+    if !parent
+        .compatible()
+        .is_some_and(|c| c.all().any(|s| COMPATIBLE.contains(&s)))
+    {
+        return None;
+    }
+    node.interrupts()?.next()
+}
+
+/// Call `handler` whenever `irq` is raised, on whichever hart claims it
+pub fn register(irq: usize, handler: fn()) -> Result<(), OsError> {
+    let mut plic = PLIC.lock();
+    let plic = plic.as_mut().ok_or(OsError::NotFound)?;
+    if irq == 0 || irq > plic.sources || plic.handlers.contains_key(&irq) {
+        return Err(OsError::InvalidParam);
+    }
+    plic.handlers.insert(irq, handler);
+    plic.set_priority(irq, 1);
+    for context in plic.contexts.iter().flatten() {
+        plic.set_enabled(*context, irq, true);
+    }
+    Ok(())
+}
+
+pub fn unregister(irq: usize) {
+    let mut plic = PLIC.lock();
+    let Some(plic) = plic.as_mut() else {
+        return;
+    };
+    if plic.handlers.remove(&irq).is_some() {
+        for context in plic.contexts.iter().flatten() {
+            plic.set_enabled(*context, irq, false);
+        }
+        plic.set_priority(irq, 0);
+    }
+}
+
+/// Claim and handle the pending external interrupts of the current hart
+pub fn handle() {
+    loop {
+        let (context, irq, handler) = {
+            let plic = PLIC.lock();
+            let Some((plic, context)) = plic
+                .as_ref()
+                .and_then(|plic| Some((plic, plic.context(tp())?)))
+            else {
+                return;
+            };
+            let irq = plic.claim(context);
+            if irq == 0 {
+                return;
+            }
+            (context, irq, plic.handlers.get(&irq).copied())
+        };
+        // Handlers may register interrupts themselves, the lock is not held
+        match handler {
+            Some(handler) => handler(),
+            None => warn!("Unhandled external interrupt {}", irq),
+        }
+        if let Some(plic) = PLIC.lock().as_ref() {
+            plic.complete(context, irq);
+        }
+    }
+}
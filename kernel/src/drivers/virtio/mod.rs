@@ -0,0 +1,257 @@
+//! Minimal virtio-mmio transport with a single polled virtqueue.
+//! See the virtio 1.2 specification, section 4.2.
+
+use core::sync::atomic::{Ordering, fence};
+
+use alloc::vec::Vec;
+
+use crate::{
+    error::OsError,
+    mm::{
+        addr::{PhysAddr, VirtAddr, kva2pa, pa2kva},
+        consts::PAGE_SIZE,
+        frame::{self, FrameTracker},
+    },
+};
+
+pub const VIRTIO_MAGIC: u32 = 0x7472_6976; // "virt"
+
+pub const DEVICE_ID_BLOCK: u32 = 2;
+
+// MMIO register offsets
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028; // Legacy only
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c; // Legacy only
+const QUEUE_PFN: usize = 0x040; // Legacy only
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG: usize = 0x100;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+
+// Feature bit 32, required by non-legacy devices
+const FEATURE_VERSION_1: u32 = 1 << 0;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// Number of descriptors, requests are issued one at a time so a few suffice
+const QUEUE_SIZE: usize = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+/// One buffer of a request, `writable` buffers are filled by the device.
+pub struct Buffer {
+    pub pa: PhysAddr,
+    pub len: usize,
+    pub writable: bool,
+}
+
+impl Buffer {
+    pub fn from_slice(buf: &[u8]) -> Self {
+        Self {
+            pa: kva2pa(VirtAddr(buf.as_ptr() as usize)),
+            len: buf.len(),
+            writable: false,
+        }
+    }
+
+    pub fn from_mut_slice(buf: &mut [u8]) -> Self {
+        Self {
+            pa: kva2pa(VirtAddr(buf.as_mut_ptr() as usize)),
+            len: buf.len(),
+            writable: true,
+        }
+    }
+}
+
+pub struct VirtioMmio {
+    base: usize,
+    version: u32,
+    // Descriptor table and available ring in the first page, used ring in the
+    // second, which is also the legacy layout with a 4 KiB alignment
+    queue: Vec<FrameTracker>,
+    last_used: u16,
+}
+
+impl VirtioMmio {
+    /// Probe the device at the kernel virtual address `base`, returning it if
+    /// it is a virtio device of `device_id`.
+    pub fn probe(base: usize, device_id: u32) -> Option<Self> {
+        let mut dev = Self {
+            base,
+            version: 0,
+            queue: Vec::new(),
+            last_used: 0,
+        };
+        if dev.read(MAGIC_VALUE) != VIRTIO_MAGIC || dev.read(DEVICE_ID) != device_id {
+            return None;
+        }
+        dev.version = dev.read(VERSION);
+        Some(dev)
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ((self.base + reg) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, val: u32) {
+        unsafe { ((self.base + reg) as *mut u32).write_volatile(val) }
+    }
+
+    /// Read a field of the device specific configuration space
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ((self.base + CONFIG + offset) as *const T).read_volatile() }
+    }
+
+    /// Reset the device, negotiate no optional features and set up queue 0.
+    pub fn init(&mut self) -> Result<(), OsError> {
+        self.write(STATUS, 0);
+        self.write(STATUS, STATUS_ACKNOWLEDGE);
+        self.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let _features = self.read(DEVICE_FEATURES);
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, 0);
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        if self.version >= 2 {
+            self.write(DRIVER_FEATURES_SEL, 1);
+            self.write(DRIVER_FEATURES, FEATURE_VERSION_1);
+            status |= STATUS_FEATURES_OK;
+            self.write(STATUS, status);
+            if self.read(STATUS) & STATUS_FEATURES_OK == 0 {
+                return Err(OsError::NoDisk);
+            }
+        } else {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        }
+
+        self.write(QUEUE_SEL, 0);
+        if (self.read(QUEUE_NUM_MAX) as usize) < QUEUE_SIZE {
+            return Err(OsError::NoDisk);
+        }
+        self.queue = frame::alloc_frames(2, 2)?;
+        let desc = PhysAddr::from(self.queue[0].ppn);
+        let avail = desc + QUEUE_SIZE * size_of::<Descriptor>();
+        let used = PhysAddr::from(self.queue[1].ppn);
+        self.write(QUEUE_NUM, QUEUE_SIZE as u32);
+        if self.version >= 2 {
+            self.write(QUEUE_DESC_LOW, desc.0 as u32);
+            self.write(QUEUE_DESC_HIGH, (desc.0 >> 32) as u32);
+            self.write(QUEUE_DRIVER_LOW, avail.0 as u32);
+            self.write(QUEUE_DRIVER_HIGH, (avail.0 >> 32) as u32);
+            self.write(QUEUE_DEVICE_LOW, used.0 as u32);
+            self.write(QUEUE_DEVICE_HIGH, (used.0 >> 32) as u32);
+            self.write(QUEUE_READY, 1);
+        } else {
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, self.queue[0].ppn.0 as u32);
+        }
+
+        self.write(STATUS, status | STATUS_DRIVER_OK);
+        Ok(())
+    }
+
+    fn descriptors(&mut self) -> &mut [Descriptor; QUEUE_SIZE] {
+        unsafe { &mut *pa2kva(self.queue[0].ppn.into()).as_mut_ptr::<[Descriptor; QUEUE_SIZE]>() }
+    }
+
+    fn avail(&mut self) -> *mut AvailRing {
+        let desc = pa2kva(self.queue[0].ppn.into());
+        (desc + QUEUE_SIZE * size_of::<Descriptor>()).as_mut_ptr::<AvailRing>()
+    }
+
+    fn used(&self) -> *const UsedRing {
+        pa2kva(self.queue[1].ppn.into()).as_ptr::<UsedRing>()
+    }
+
+    /// Submit a chain of buffers to queue 0 and busy-wait until the device
+    /// has consumed it.
+    pub fn transfer(&mut self, buffers: &[Buffer]) {
+        debug_assert!(!buffers.is_empty() && buffers.len() <= QUEUE_SIZE);
+        let descs = self.descriptors();
+        for (i, buf) in buffers.iter().enumerate() {
+            let mut flags = if buf.writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            descs[i] = Descriptor {
+                addr: buf.pa.0 as u64,
+                len: buf.len as u32,
+                flags,
+                next: (i + 1) as u16,
+            };
+        }
+        let avail = self.avail();
+        unsafe {
+            let idx = (&raw const (*avail).idx).read_volatile();
+            (&raw mut (*avail).ring[idx as usize % QUEUE_SIZE]).write_volatile(0);
+            fence(Ordering::SeqCst);
+            (&raw mut (*avail).idx).write_volatile(idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+        self.write(QUEUE_NOTIFY, 0);
+
+        let used = self.used();
+        while unsafe { (&raw const (*used).idx).read_volatile() } == self.last_used {
+            core::hint::spin_loop();
+        }
+        fence(Ordering::SeqCst);
+        self.last_used = self.last_used.wrapping_add(1);
+        // The queue is polled, just acknowledge the interrupt it raised
+        self.write(INTERRUPT_ACK, self.read(INTERRUPT_STATUS));
+    }
+}
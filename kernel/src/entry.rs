@@ -65,14 +65,15 @@ unsafe extern "C" fn _high_entry() -> ! {
 pub static mut BOOT_PAGE_TABLE: [PageTableEntry; 512] = {
     let mut table = [PageTableEntry::EMPTY; 512];
     let ppn = PhysPageNum(0x80000);
-    let flags = PteFlags::from_bits_truncate(0xcf); // VRWXAD
+    let flags = PteFlags::from_bits_truncate(0xef); // VRWXGAD
     table[2] = PageTableEntry::new(ppn, flags); // 0x0000_0000_8000_0000
     table[448] = PageTableEntry::new(ppn, flags); // 0xffff_fff0_0000_0000 - 0xffff_fff0_4000_0000
     table
 };
 
+// Only used until a hart switches to its guarded stack, see `mm::kstack`
 #[repr(C, align(4096))]
-struct KernelStack([u8; 1 << 20]); // 1MiB stack
+struct KernelStack([u8; 1 << 18]); // 256KiB stack
 
 #[unsafe(link_section = ".bss.stack")]
 static mut KERNEL_STACK: core::mem::MaybeUninit<[KernelStack; CPU_NUM]> =
@@ -82,7 +83,7 @@ static mut KERNEL_STACK: core::mem::MaybeUninit<[KernelStack; CPU_NUM]> =
 unsafe extern "C" fn set_stack(hartid: usize) {
     naked_asm!(
         "   add  t0, a0, 1
-            slli t0, t0, 20
+            slli t0, t0, 18
             la   sp, {stack}
             add  sp, sp, t0
             ret
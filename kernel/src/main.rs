@@ -30,6 +30,7 @@ mod error;
 mod logging;
 mod mm;
 mod panic;
+mod random;
 mod syscall;
 mod task;
 mod timer;
@@ -54,13 +55,26 @@ extern "C" fn kernel_main(hartid: usize, dtb: usize) -> Infallible {
     print!("{}", BANNER);
     info!("RVOS Started on hart {hartid}");
     STARTED_HART.fetch_add(1, Ordering::SeqCst);
-    let _device_tree = device_tree::parse_fdt(dtb);
-    mm::init();
+    let device_tree = device_tree::parse_fdt(dtb);
+    random::init(&device_tree);
+    mm::init(&device_tree, dtb);
     mm::map_kernel_regions(dtb);
+    mm::kstack::init();
+    mm::paging::asid::init();
+    if !drivers::plic::init(&device_tree) {
+        warn!("No PLIC found, external interrupts disabled.");
+    }
+    if drivers::block::init(&device_tree) {
+        mm::swap::init();
+        mm::file::init();
+    } else {
+        warn!("No block device found, swap and file mappings disabled.");
+    }
     trap::init();
     console::CONSOLE.init();
     console::CUSTOM_PRINT.store(true, Ordering::SeqCst);
     info!("Switched to custom uart driver.");
+    console::init_input(&device_tree);
     timer::init();
     #[cfg(feature = "smp")]
     {
@@ -90,7 +104,7 @@ extern "C" fn kernel_main(hartid: usize, dtb: usize) -> Infallible {
     //     riscv::asm::ebreak();
     // }
 
-    task::run()
+    unsafe { mm::kstack::switch_to(hartid, task::run) }
 }
 
 #[unsafe(no_mangle)]
@@ -99,7 +113,7 @@ pub extern "C" fn other_hart_main(hartid: usize) -> ! {
     trap::init();
     info!("Hart {hartid} started.");
     riscv::asm::wfi();
-    task::schedule::SCHEDULER.hart_loop()
+    unsafe { mm::kstack::switch_to(hartid, || task::schedule::SCHEDULER.hart_loop()) }
 }
 
 fn clear_bss() {
@@ -108,11 +122,8 @@ fn clear_bss() {
         fn __bss_end();
     }
     unsafe {
-        core::ptr::write_bytes(
-            __bss_start as *mut u8,
-            0,
-            (__bss_end as usize - __bss_start as usize) as usize,
-        );
+        let (start, end) = (__bss_start as *mut u8, __bss_end as *const u8);
+        core::ptr::write_bytes(start, 0, end as usize - start as usize);
     }
 }
 
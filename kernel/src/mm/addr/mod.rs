@@ -21,10 +21,15 @@ pub fn pa2kva(pa: PhysAddr) -> VirtAddr {
     VirtAddr(pa.0 + KERNEL_OFFSET)
 }
 
+/// Physical address of `va` in the linear map. Kernel stacks and the heap
+/// growth window are mapped elsewhere, their addresses have to be translated
+/// through the page table.
 pub fn kva2pa(va: VirtAddr) -> PhysAddr {
-    if !(K_PHYSICAL_MEMORY_BEG..K_PHYSICAL_MEMORY_BEG + unsafe { MEMORY_SIZE }).contains(&va.0) {
-        warn!("Address not in kernel virtual memory range");
-    }
+    assert!(
+        (K_PHYSICAL_MEMORY_BEG..K_PHYSICAL_MEMORY_BEG + unsafe { MEMORY_SIZE }).contains(&va.0),
+        "{:#x} is not in the linear map",
+        va.0
+    );
     PhysAddr(va.0 - KERNEL_OFFSET)
 }
 
@@ -7,6 +7,10 @@ pub const K_BEG: usize = 0xffff_ffc0_0000_0000;
 pub const K_VIRTUAL_MEMORY_BEG: usize = 0xffff_ffc0_0000_0000;
 pub const K_VIRTUAL_MEMORY_END: usize = 0xffff_ffd0_0000_0000;
 
+// Kernel stacks and their guard pages, a 1 GiB aligned part of the above
+pub const K_STACK_BEG: usize = 0xffff_ffc8_0000_0000;
+pub const K_STACK_END: usize = 0xffff_ffc8_4000_0000;
+
 pub const K_FILE_MAPPING_BEG: usize = 0xffff_ffd0_0000_0000;
 pub const K_FILE_MAPPING_END: usize = 0xffff_ffe0_0000_0000;
 
@@ -44,5 +48,8 @@ pub fn is_illegal_user_va(va: usize) -> bool {
 
 #[inline]
 pub fn is_illegal_user_va_range(va: usize, size: usize) -> bool {
-    is_illegal_user_va(va) || is_illegal_user_va(va + size - 1)
+    match va.checked_add(size) {
+        Some(end) => va < U_BEG || end > U_END,
+        None => true,
+    }
 }
@@ -0,0 +1,270 @@
+use core::ops::Range;
+
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use log::info;
+use sync::OnceCell;
+
+use crate::{
+    Mutex,
+    drivers::block::{self, BLOCK_SIZE, MBR_TYPE_SWAP},
+    error::OsError,
+    task::user_space::UserPageFaultError,
+};
+
+use super::{addr::PhysAddr, consts::PAGE_SIZE, frame::FrameTracker, page::PageFlags};
+
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SIZE;
+
+// Files which can be opened by name
+static FILES: OnceCell<Vec<(String, Arc<File>)>> = OnceCell::new();
+
+/// Storage behind a `File`, accessed a page at a time
+pub trait FileBacking: Send + Sync {
+    /// Size in bytes
+    fn len(&self) -> usize;
+
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> Result<(), OsError>;
+
+    fn write_page(&self, index: usize, buf: &[u8]) -> Result<(), OsError>;
+}
+
+/// A file which can be mapped into user space. Every mapping of a page shares
+/// the frame in the page cache.
+///
+/// Like swap, the page cache does no I/O where pages are mapped, as the
+/// address space is locked there. Reads are queued and done by `run_io`,
+/// dirty pages are written back by `run_io` or `File::run_io`.
+pub struct File {
+    backing: Box<dyn FileBacking>,
+    cache: Mutex<BTreeMap<usize, CachedPage>>,
+}
+
+struct CachedPage {
+    frame: Arc<FrameTracker>,
+    state: PageState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageState {
+    /// To be read from the backing store
+    Wanted,
+    Reading,
+    /// Same as the backing store
+    Clean,
+    /// Written to since it was last written back
+    Dirty,
+    Writing,
+}
+
+impl File {
+    pub fn new(backing: impl FileBacking + 'static) -> Arc<Self> {
+        Arc::new(Self {
+            backing: Box::new(backing),
+            cache: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.backing.len()
+    }
+
+    pub fn pages(&self) -> usize {
+        self.len().div_ceil(PAGE_SIZE)
+    }
+
+    /// The cached frame of page `index`. On a miss, a frame from `alloc` is
+    /// queued to be read into and `SwapIo` returned.
+    pub fn page(
+        &self,
+        index: usize,
+        alloc: impl FnOnce() -> Result<FrameTracker, UserPageFaultError>,
+    ) -> Result<Arc<FrameTracker>, UserPageFaultError> {
+        if index >= self.pages() {
+            return Err(UserPageFaultError::Io);
+        }
+        let cached = |cache: &BTreeMap<usize, CachedPage>| {
+            cache.get(&index).map(|page| match page.state {
+                PageState::Wanted | PageState::Reading => Err(UserPageFaultError::SwapIo),
+                _ => Ok(page.frame.clone()),
+            })
+        };
+        if let Some(result) = cached(&self.cache.lock()) {
+            return result;
+        }
+        // Allocating may shrink the page cache, so it is not locked
+        let frame = Arc::new(alloc()?);
+        let mut cache = self.cache.lock();
+        if let Some(result) = cached(&cache) {
+            return result;
+        }
+        let state = PageState::Wanted;
+        cache.insert(index, CachedPage { frame, state });
+        Err(UserPageFaultError::SwapIo)
+    }
+
+    /// Whether `frame` is the cached frame of page `index`
+    pub fn caches(&self, index: usize, frame: &Arc<FrameTracker>) -> bool {
+        self.cache
+            .lock()
+            .get(&index)
+            .is_some_and(|page| Arc::ptr_eq(&page.frame, frame))
+    }
+
+    /// Page `index` was written to through a mapping. A write back in flight
+    /// leaves it dirty.
+    pub fn mark_dirty(&self, index: usize) {
+        if let Some(page) = self.cache.lock().get_mut(&index)
+            && matches!(page.state, PageState::Clean | PageState::Writing)
+        {
+            page.state = PageState::Dirty;
+        }
+    }
+
+    /// Do the queued reads and write the dirty pages back in `range`. Must be
+    /// called with no address space locked.
+    pub fn run_io(&self, range: Range<usize>) -> Result<(), OsError> {
+        let mut result = Ok(());
+        let mut next = range.start;
+        loop {
+            let (index, frame, state) = {
+                let mut cache = self.cache.lock();
+                let Some((&index, page)) = cache
+                    .range_mut(next..range.end)
+                    .find(|(_, page)| matches!(page.state, PageState::Wanted | PageState::Dirty))
+                else {
+                    return result;
+                };
+                page.state = match page.state {
+                    PageState::Wanted => PageState::Reading,
+                    _ => PageState::Writing,
+                };
+                (index, page.frame.clone(), page.state)
+            };
+            next = index + 1;
+            let io = if state == PageState::Reading {
+                let buf = unsafe { PhysAddr::from(frame.ppn).as_mut_page_slice() };
+                frame.page().clear_flags(PageFlags::ZEROED);
+                self.backing.read_page(index, buf)
+            } else {
+                let buf = unsafe { PhysAddr::from(frame.ppn).as_page_slice() };
+                self.backing.write_page(index, buf)
+            };
+            let mut cache = self.cache.lock();
+            let page = cache.get_mut(&index).unwrap();
+            match (state, io) {
+                (PageState::Reading, Ok(())) => page.state = PageState::Clean,
+                (PageState::Reading, Err(e)) => {
+                    // The fault which wanted the page fails
+                    cache.remove(&index);
+                    result = result.and(Err(e));
+                }
+                // Unless written to again meanwhile
+                (_, Ok(())) if page.state == PageState::Writing => page.state = PageState::Clean,
+                (_, Ok(())) => {}
+                (_, Err(e)) => {
+                    page.state = PageState::Dirty;
+                    result = result.and(Err(e));
+                }
+            }
+        }
+    }
+
+    /// Drop the clean cached pages no one maps, returns how many frames were
+    /// freed
+    fn shrink(&self) -> usize {
+        let mut cache = self.cache.lock();
+        let before = cache.len();
+        cache
+            .retain(|_, page| page.state != PageState::Clean || Arc::strong_count(&page.frame) > 1);
+        before - cache.len()
+    }
+}
+
+/// A range of blocks on the block device
+pub struct BlockRange {
+    first_block: usize,
+    blocks: usize,
+}
+
+impl BlockRange {
+    pub fn new(first_block: usize, blocks: usize) -> Self {
+        Self {
+            first_block,
+            blocks,
+        }
+    }
+
+    fn page_block(&self, index: usize) -> Result<usize, OsError> {
+        if (index + 1) * BLOCKS_PER_PAGE > self.blocks {
+            return Err(OsError::InvalidParam);
+        }
+        Ok(self.first_block + index * BLOCKS_PER_PAGE)
+    }
+}
+
+impl FileBacking for BlockRange {
+    fn len(&self) -> usize {
+        self.blocks * BLOCK_SIZE
+    }
+
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> Result<(), OsError> {
+        let block = self.page_block(index)?;
+        block::with_device(|dev| dev.read_blocks(block, buf))
+    }
+
+    fn write_page(&self, index: usize, buf: &[u8]) -> Result<(), OsError> {
+        let block = self.page_block(index)?;
+        block::with_device(|dev| dev.write_blocks(block, buf))
+    }
+}
+
+/// Expose the partitions of the block device other than swap as files named
+/// after them, `vda1` for the first one.
+pub fn init() {
+    let files: Vec<_> = block::partitions()
+        .into_iter()
+        .filter(|partition| partition.ty != MBR_TYPE_SWAP)
+        .map(|partition| {
+            let blocks = partition.blocks / BLOCKS_PER_PAGE * BLOCKS_PER_PAGE;
+            info!(
+                "Partition {} can be mapped, {} KiB.",
+                partition.number,
+                blocks * BLOCK_SIZE / 1024
+            );
+            (
+                format!("vda{}", partition.number),
+                File::new(BlockRange::new(partition.first_block, blocks)),
+            )
+        })
+        .collect();
+    let _ = FILES.initialize(|| files);
+}
+
+/// The file called `name`
+pub fn open(name: &str) -> Option<Arc<File>> {
+    FILES
+        .get()?
+        .iter()
+        .find(|(file_name, _)| file_name == name)
+        .map(|(_, file)| file.clone())
+}
+
+/// Do the queued reads and write every dirty cached page back, see
+/// `File::run_io`
+pub fn run_io() -> Result<(), OsError> {
+    let mut result = Ok(());
+    for (_, file) in FILES.get().into_iter().flatten() {
+        result = result.and(file.run_io(0..file.pages()));
+    }
+    result
+}
+
+/// Drop the clean cached pages no one maps, returns how many frames were freed
+pub fn shrink() -> usize {
+    FILES
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|(_, file)| file.shrink())
+        .sum()
+}
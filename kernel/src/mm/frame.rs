@@ -1,20 +1,32 @@
-use core::fmt;
+use core::{fmt, ops::Range};
 
-use alloc::vec::Vec;
+use alloc::{sync::Arc, vec::Vec};
 use log::{info, trace, warn};
 
+use sync::Lazy;
+
 use crate::{Mutex, error::OsError, prev_pow_of_2};
 
 use super::{
     addr::{PhysAddr, PhysPageNum, pa2kva},
+    address_space::PHYSICAL_MEMORY_START,
     consts::FRAME_SIZE,
+    page::{self, Page, PageFlags},
 };
 
-const ORDER: usize = 32;
+pub const ORDER: usize = 32;
 
 static FRAME_ALLOCATOR: Mutex<FrameAllocator<ORDER>> = Mutex::new(FrameAllocator::new());
 
-#[derive(Debug, Clone)]
+// Shared by every read-only demand-zero mapping, never written
+static ZERO_FRAME: Lazy<Arc<FrameTracker>> = Lazy::new(|| {
+    let frame = alloc().expect("failed to allocate the zero frame");
+    frame.page().set_flags(PageFlags::PINNED);
+    Arc::new(frame)
+});
+
+/// Owning reference to a frame, the frame is freed when the last one is dropped.
+#[derive(Debug)]
 pub struct FrameTracker {
     pub ppn: PhysPageNum,
 }
@@ -22,19 +34,73 @@ pub struct FrameTracker {
 impl FrameTracker {
     pub fn new(ppn: PhysPageNum) -> Self {
         trace!("Allocating Frame {ppn:?}");
+        page::lookup(ppn).inc_ref();
         FrameTracker { ppn }
     }
+
+    pub fn page(&self) -> &'static Page {
+        page::lookup(self.ppn)
+    }
+}
+
+impl Clone for FrameTracker {
+    fn clone(&self) -> Self {
+        self.page().inc_ref();
+        FrameTracker { ppn: self.ppn }
+    }
 }
 
 impl Drop for FrameTracker {
     fn drop(&mut self) {
-        trace!("Dropping Frame {:?}", self.ppn);
-        dealloc(self.ppn);
+        let page = self.page();
+        if page.dec_ref() == 0 {
+            trace!("Dropping Frame {:?}", self.ppn);
+            page.reset();
+            dealloc(self.ppn);
+        }
     }
 }
 
+// Index of a frame relative to the first managed frame, `NIL` terminates the
+// free lists.
+type FrameIdx = u32;
+const NIL: FrameIdx = FrameIdx::MAX;
+
+/// Allocator state of a frame. Only the head frame of a free block has `free`
+/// set, which makes checking whether a buddy can be merged O(1).
+#[derive(Debug, Clone, Copy)]
+struct FrameInfo {
+    prev: FrameIdx,
+    next: FrameIdx,
+    order: u8,
+    free: bool,
+}
+
+impl FrameInfo {
+    const EMPTY: Self = Self {
+        prev: NIL,
+        next: NIL,
+        order: 0,
+        free: false,
+    };
+}
+
+/// Snapshot of the allocator counters, in frames unless noted otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+    /// Number of free blocks of `1 << order` frames
+    pub free_blocks: [usize; ORDER],
+}
+
 pub struct FrameAllocator<const ORDER: usize> {
-    free_list: [Vec<PhysPageNum>; ORDER],
+    // First frame covered by `info`, the metadata itself is stored there
+    base: PhysPageNum,
+    info: &'static mut [FrameInfo],
+    free_head: [FrameIdx; ORDER],
+    free_blocks: [usize; ORDER],
     total: usize,
     allocated: usize,
 }
@@ -42,25 +108,93 @@ pub struct FrameAllocator<const ORDER: usize> {
 impl<const ORDER: usize> FrameAllocator<ORDER> {
     const fn new() -> Self {
         FrameAllocator {
-            free_list: [const { Vec::new() }; ORDER],
+            base: PhysPageNum(0),
+            info: &mut [],
+            free_head: [NIL; ORDER],
+            free_blocks: [0; ORDER],
             total: 0,
             allocated: 0,
         }
     }
 
-    pub fn init(&mut self, start: PhysAddr, end: PhysAddr) {
-        debug_assert!(start.0 <= end.0);
-        let start = start.ceil_page();
+    /// Place the allocator state of the frames below `end` at `meta`. No
+    /// frame is free until it is added with `add_free`.
+    pub fn init(&mut self, meta: PhysAddr, end: PhysAddr) {
+        let start = PhysAddr(PHYSICAL_MEMORY_START).floor_page();
+        let frames = end.floor_page().0 - start.0;
+        assert!(frames < NIL as usize, "too many frames to manage");
+        self.info = unsafe {
+            let ptr = pa2kva(meta).as_mut_ptr::<FrameInfo>();
+            core::slice::from_raw_parts_mut(ptr, frames)
+        };
+        self.info.fill(FrameInfo::EMPTY);
+        self.base = start;
+    }
+
+    /// Hand the frames in `[start, end)` to the allocator
+    pub fn add_free(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut current = start.ceil_page();
         let end = end.floor_page();
-        let mut current = start;
+        for ppn in current.0..end.0 {
+            page::lookup(PhysPageNum(ppn)).clear_flags(PageFlags::KERNEL | PageFlags::PINNED);
+        }
         while current < end {
             let lowbit = 1 << current.0.trailing_zeros();
             let size = usize::min(lowbit, prev_pow_of_2!(end.0 - current.0));
-            let order = size.trailing_zeros() as usize;
-            self.free_list[order].push(current);
-            current += size;
+            let order = usize::min(size.trailing_zeros() as usize, ORDER - 1);
+            self.push(current, order);
+            current += 1 << order;
+            self.total += 1 << order;
+        }
+    }
+
+    fn index(&self, ppn: PhysPageNum) -> Option<FrameIdx> {
+        ppn.0
+            .checked_sub(self.base.0)
+            .filter(|&idx| idx < self.info.len())
+            .map(|idx| idx as FrameIdx)
+    }
+
+    fn push(&mut self, ppn: PhysPageNum, order: usize) {
+        let idx = self.index(ppn).expect("frame out of range");
+        let head = self.free_head[order];
+        self.info[idx as usize] = FrameInfo {
+            prev: NIL,
+            next: head,
+            order: order as u8,
+            free: true,
+        };
+        if head != NIL {
+            self.info[head as usize].prev = idx;
+        }
+        self.free_head[order] = idx;
+        self.free_blocks[order] += 1;
+    }
+
+    fn remove(&mut self, idx: FrameIdx) {
+        let FrameInfo {
+            prev, next, order, ..
+        } = self.info[idx as usize];
+        let order = order as usize;
+        if prev != NIL {
+            self.info[prev as usize].next = next;
+        } else {
+            self.free_head[order] = next;
+        }
+        if next != NIL {
+            self.info[next as usize].prev = prev;
         }
-        self.total = end.0 - start.0;
+        self.info[idx as usize] = FrameInfo::EMPTY;
+        self.free_blocks[order] -= 1;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<PhysPageNum> {
+        let idx = self.free_head[order];
+        if idx == NIL {
+            return None;
+        }
+        self.remove(idx);
+        Some(self.base + idx as usize)
     }
 
     pub fn alloc(&mut self, size: usize, align: usize) -> Option<PhysPageNum> {
@@ -73,17 +207,12 @@ impl<const ORDER: usize> FrameAllocator<ORDER> {
         let align_order = align.trailing_zeros() as usize;
         let start_order = usize::max(order, align_order);
         for i in start_order..ORDER {
-            if !self.free_list[i].is_empty() {
-                for j in ((order + 1)..=i).rev() {
-                    let ppn = self.free_list[j]
-                        .pop()
-                        .expect("There should be some free frames");
-                    self.free_list[j - 1].push(PhysPageNum(ppn.0 + (1 << (j - 1)))); // This is the buddy frame
-                    self.free_list[j - 1].push(ppn); // This is the allocated frame, which matches the align
+            if let Some(ppn) = self.pop(i) {
+                // Return the upper halves to the free lists, the block keeps
+                // the alignment of the larger one
+                for j in (order..i).rev() {
+                    self.push(ppn + (1 << j), j);
                 }
-                let ppn = self.free_list[order]
-                    .pop()
-                    .expect("There should be some free frames");
                 self.allocated += 1 << order;
                 return Some(ppn);
             }
@@ -91,28 +220,39 @@ impl<const ORDER: usize> FrameAllocator<ORDER> {
         None
     }
 
-    pub fn dealloc(&mut self, frame: PhysPageNum) {
+    /// Free `size` frames starting at `frame`, which must have been allocated
+    /// as a part of a block at least that large.
+    pub fn dealloc(&mut self, frame: PhysPageNum, size: usize) {
+        debug_assert!(size.is_power_of_two());
         let mut ppn = frame;
-        let mut order = 0;
+        let mut order = size.trailing_zeros() as usize;
         while order < ORDER - 1 {
             let buddy = PhysPageNum(ppn.0 ^ (1 << order));
-            let mut found = false;
-            for block in &self.free_list[order] {
-                if *block == buddy {
-                    found = true;
-                    break;
+            match self.index(buddy) {
+                Some(idx)
+                    if self.info[idx as usize].free
+                        && self.info[idx as usize].order as usize == order =>
+                {
+                    self.remove(idx);
+                    ppn = PhysPageNum(ppn.0 & buddy.0);
+                    order += 1;
                 }
+                _ => break,
             }
-            if found {
-                self.free_list[order].retain(|x| *x != buddy);
-                ppn = PhysPageNum(ppn.0 & buddy.0);
-                order += 1;
-            } else {
-                break;
-            }
         }
-        self.free_list[order].push(ppn);
-        self.allocated -= 1;
+        self.push(ppn, order);
+        self.allocated -= size;
+    }
+}
+
+impl FrameAllocator<ORDER> {
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            free: self.total - self.allocated,
+            used: self.allocated,
+            free_blocks: self.free_blocks,
+        }
     }
 }
 
@@ -120,17 +260,9 @@ impl fmt::Debug for FrameAllocator<ORDER> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "FrameAllocator {{")?;
         writeln!(f, "  total: {}, allocated: {}", self.total, self.allocated)?;
-        writeln!(f, "  free_list: [")?;
-        for i in 0..ORDER {
-            if !self.free_list[i].is_empty() {
-                write!(f, "    order {i:2}: ")?;
-                for ppn in &self.free_list[i] {
-                    write!(f, "{ppn} ")?;
-                }
-                writeln!(f)?;
-            } else {
-                writeln!(f, "    order {i:2}: empty")?;
-            }
+        writeln!(f, "  free_blocks: [")?;
+        for (i, blocks) in self.free_blocks.iter().enumerate() {
+            writeln!(f, "    order {i:2}: {}", blocks)?;
         }
         writeln!(f, "  ]")?;
         writeln!(f, "}}")
@@ -148,11 +280,21 @@ pub fn debug_print() {
     log::debug!("\n{:#?}", FRAME_ALLOCATOR.lock());
 }
 
-pub fn init(start: PhysAddr, end: PhysAddr) {
-    FRAME_ALLOCATOR.lock().init(start, end);
+/// Bytes of allocator state needed for the frames below `end`
+pub fn metadata_size(end: PhysAddr) -> usize {
+    (end.floor_page().0 - PhysAddr(PHYSICAL_MEMORY_START).floor_page().0) * size_of::<FrameInfo>()
+}
+
+/// Set up the allocator with its state at `meta` and the frames in `free`
+pub fn init(meta: PhysAddr, end: PhysAddr, free: &[Range<usize>]) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    allocator.init(meta, end);
+    for range in free {
+        allocator.add_free(PhysAddr(range.start), PhysAddr(range.end));
+    }
     info!(
         "Initialized frame allocator with {} frames in total.",
-        FRAME_ALLOCATOR.lock().total
+        allocator.total
     );
 }
 
@@ -168,7 +310,11 @@ pub fn alloc_frames(size: usize, align: usize) -> Result<Vec<FrameTracker>, OsEr
     frame
         .map(|frame| {
             (0..size)
-                .map(|i| FrameTracker::new(PhysPageNum(frame.0 + i)))
+                .map(|i| {
+                    let frame = FrameTracker::new(PhysPageNum(frame.0 + i));
+                    frame.page().set_flags(PageFlags::ZEROED);
+                    frame
+                })
                 .collect()
         })
         .ok_or(OsError::NoMem)
@@ -178,6 +324,36 @@ pub fn alloc() -> Result<FrameTracker, OsError> {
     alloc_frames(1, 1).map(|mut v| v.pop().unwrap())
 }
 
+/// Allocate `size` contiguous frames for the kernel heap. Unlike
+/// `alloc_frames` this does not touch the heap, the frames are neither
+/// tracked nor cleared and must be given back with `dealloc_kernel`.
+pub fn alloc_kernel(size: usize, align: usize) -> Option<PhysPageNum> {
+    let frame = FRAME_ALLOCATOR.lock().alloc(size, align)?;
+    for i in 0..size {
+        page::lookup(frame + i).set_flags(PageFlags::KERNEL | PageFlags::PINNED);
+    }
+    Some(frame)
+}
+
+pub fn dealloc_kernel(frame: PhysPageNum, size: usize) {
+    for i in 0..size {
+        page::lookup(frame + i).reset();
+    }
+    FRAME_ALLOCATOR.lock().dealloc(frame, size);
+}
+
 pub fn dealloc(frame: PhysPageNum) {
-    FRAME_ALLOCATOR.lock().dealloc(frame);
+    FRAME_ALLOCATOR.lock().dealloc(frame, 1);
+}
+
+pub fn zero_frame() -> Arc<FrameTracker> {
+    ZERO_FRAME.clone()
+}
+
+pub fn is_zero_frame(ppn: PhysPageNum) -> bool {
+    ZERO_FRAME.ppn == ppn
+}
+
+pub fn stats() -> FrameStats {
+    FRAME_ALLOCATOR.lock().stats()
 }
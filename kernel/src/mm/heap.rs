@@ -1,15 +1,78 @@
-use core::ptr::addr_of_mut;
+use core::{
+    alloc::Layout,
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
-use allocator::LockedAllocator;
+use allocator::{Heap, SlabAllocator};
 use log::info;
 
-use crate::config::KERNEL_HEAP_SIZE;
+use crate::{
+    Mutex,
+    config::{CPU_NUM, KERNEL_HEAP_MAX_GROWTH, KERNEL_HEAP_SHRINK_INTERVAL, KERNEL_HEAP_SIZE},
+    entry::BOOT_PAGE_TABLE,
+    task::taskdef::TaskControlBlock,
+    timer,
+    trap::context::UserContext,
+};
+
+use super::{
+    addr::{VirtAddr, VirtPageNum, kva2pa},
+    address_space::K_VIRTUAL_MEMORY_BEG,
+    consts::{MEGA_PAGE_SIZE, PAGE_SIZE},
+    frame,
+    paging::{
+        page_table::{PageSize, PageTable},
+        pte::PteFlags,
+        tlb,
+    },
+};
+
+const HEAP_ORDER: usize = 32;
+
+// The heap grows by megapages mapped into this window
+const GROWTH_BASE: usize = K_VIRTUAL_MEMORY_BEG;
+const CHUNKS: usize = KERNEL_HEAP_MAX_GROWTH / MEGA_PAGE_SIZE;
+const CHUNK_FRAMES: usize = MEGA_PAGE_SIZE / PAGE_SIZE;
 
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedAllocator<32> = LockedAllocator::new();
+static HEAP_ALLOCATOR: SlabAllocator<HEAP_ORDER, CPU_NUM> =
+    SlabAllocator::with_rescue(arch::tp, grow);
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
+// Set once the page tables of the growth window exist
+static GROWABLE: AtomicBool = AtomicBool::new(false);
+
+// Chunks of the growth window which are mapped. Always locked after the heap.
+static GROWN: Mutex<[u64; CHUNKS / 64]> = Mutex::new([0; CHUNKS / 64]);
+
+// Time in milliseconds before which `idle_shrink` does nothing
+static NEXT_SHRINK: AtomicUsize = AtomicUsize::new(0);
+
+fn is_grown(grown: &[u64], chunk: usize) -> bool {
+    grown[chunk / 64] & (1 << (chunk % 64)) != 0
+}
+
+fn chunk_vpn(chunk: usize) -> VirtPageNum {
+    VirtAddr(GROWTH_BASE + chunk * MEGA_PAGE_SIZE).floor_page()
+}
+
+fn kernel_page_table() -> PageTable {
+    unsafe { PageTable::from_ppn(kva2pa(VirtAddr(addr_of!(BOOT_PAGE_TABLE) as usize)).into()) }
+}
+
+/// Size of the allocation behind an `Arc<T>`
+fn arc_size<T>() -> usize {
+    let counts = Layout::new::<[usize; 2]>();
+    let (layout, _) = counts.extend(Layout::new::<T>()).unwrap();
+    layout.pad_to_align().size()
+}
+
 pub fn init() {
+    // Caches for the objects created with every task
+    HEAP_ALLOCATOR.add_cache(arc_size::<TaskControlBlock>());
+    HEAP_ALLOCATOR.add_cache(size_of::<UserContext>());
+    // SAFETY: nothing but the heap uses `HEAP_SPACE`
     unsafe {
         HEAP_ALLOCATOR
             .lock()
@@ -21,6 +84,106 @@ pub fn init() {
     );
 }
 
+/// Create the page tables of the growth window, so that growing maps
+/// megapages without allocating. Must run before any user page table copies
+/// the kernel root table.
+pub fn init_growth() {
+    let mut pt = kernel_page_table();
+    for chunk in (0..CHUNKS).step_by(PageSize::Size1GiB.pages() / CHUNK_FRAMES) {
+        pt.find_create(chunk_vpn(chunk), PageSize::Size2MiB);
+    }
+    // The tables belong to the kernel page table from now on
+    core::mem::forget(pt);
+    GROWABLE.store(true, Ordering::Release);
+}
+
+/// Rescue function of the heap, maps enough megapages for `layout`
+fn grow(heap: &mut Heap<HEAP_ORDER>, layout: &Layout) {
+    if !GROWABLE.load(Ordering::Acquire) {
+        return;
+    }
+    let size = usize::max(layout.size(), layout.align())
+        .next_power_of_two()
+        .max(MEGA_PAGE_SIZE);
+    let chunks = size / MEGA_PAGE_SIZE;
+    let mut grown = GROWN.lock();
+    // Keep the new range aligned to its size so the buddy heap takes it as a
+    // single block
+    let Some(first) = (0..CHUNKS)
+        .step_by(chunks)
+        .find(|&c| (c..c + chunks).all(|i| !is_grown(&*grown, i)))
+    else {
+        return;
+    };
+    let mut pt = kernel_page_table();
+    for i in 0..chunks {
+        let Some(ppn) = frame::alloc_kernel(CHUNK_FRAMES, CHUNK_FRAMES) else {
+            (first..first + i).for_each(|chunk| unmap_chunk(&mut pt, chunk));
+            return;
+        };
+        pt.map_huge(
+            chunk_vpn(first + i),
+            ppn,
+            PteFlags::R | PteFlags::W | PteFlags::G | PteFlags::A | PteFlags::D,
+            PageSize::Size2MiB,
+        );
+    }
+    (first..first + chunks).for_each(|chunk| grown[chunk / 64] |= 1 << (chunk % 64));
+    let start = VirtAddr::from(chunk_vpn(first)).0;
+    tlb::shootdown_kernel(start, size);
+    // SAFETY: the chunks were free in the window and are now mapped
+    unsafe { heap.add_size(start, size) };
+}
+
+fn unmap_chunk(pt: &mut PageTable, chunk: usize) {
+    let vpn = chunk_vpn(chunk);
+    let ppn = pt.find(vpn).unwrap().0.ppn();
+    pt.unmap_huge(vpn, PageSize::Size2MiB);
+    tlb::shootdown_kernel(VirtAddr::from(vpn).0, MEGA_PAGE_SIZE);
+    frame::dealloc_kernel(ppn, CHUNK_FRAMES);
+}
+
+/// Give the chunks the heap grew by which are entirely free back to the frame
+/// allocator, returns how many were released.
+pub fn shrink() -> usize {
+    let mut freed = [0u64; CHUNKS / 64];
+    {
+        let mut heap = HEAP_ALLOCATOR.lock();
+        let grown = GROWN.lock();
+        for chunk in (0..CHUNKS).filter(|&c| is_grown(&*grown, c)) {
+            let start = VirtAddr::from(chunk_vpn(chunk)).0;
+            if heap.remove_free(start, MEGA_PAGE_SIZE) {
+                freed[chunk / 64] |= 1 << (chunk % 64);
+            }
+        }
+    }
+    // The chunks are out of the heap, so they stay marked as grown until
+    // they are unmapped
+    let mut pt = kernel_page_table();
+    let mut count = 0;
+    for chunk in (0..CHUNKS).filter(|&c| is_grown(&freed, c)) {
+        unmap_chunk(&mut pt, chunk);
+        GROWN.lock()[chunk / 64] &= !(1 << (chunk % 64));
+        count += 1;
+    }
+    count
+}
+
+/// `shrink` for idle harts, at most once per `KERNEL_HEAP_SHRINK_INTERVAL`
+/// across all of them
+pub fn idle_shrink() {
+    let now = timer::get_time_ms();
+    let next = NEXT_SHRINK.load(Ordering::Relaxed);
+    let deadline = now + KERNEL_HEAP_SHRINK_INTERVAL;
+    if now >= next
+        && NEXT_SHRINK
+            .compare_exchange(next, deadline, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        shrink();
+    }
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
     panic!("Allocation error: {:?}", layout)
@@ -0,0 +1,73 @@
+//! Per-hart kernel stacks. Each hart owns a slot of twice the stack size in
+//! the kernel stack region, the lower half is left unmapped as a guard so an
+//! overflow faults instead of running into the stack of another hart.
+
+use core::{arch::asm, ops::Range, ptr::addr_of};
+
+use crate::{
+    config::{CPU_NUM, KERNEL_STACK_SIZE},
+    entry::BOOT_PAGE_TABLE,
+};
+
+use super::{
+    addr::{VirtAddr, kva2pa},
+    address_space::{K_STACK_BEG, K_STACK_END},
+    consts::PAGE_SIZE,
+    frame,
+    page::PageFlags,
+    paging::{page_table::PageTable, pte::PteFlags},
+};
+
+pub const SLOT_SIZE: usize = 2 * KERNEL_STACK_SIZE;
+
+const _: () = assert!(KERNEL_STACK_SIZE.is_power_of_two());
+const _: () = assert!(CPU_NUM * SLOT_SIZE <= K_STACK_END - K_STACK_BEG);
+
+/// The mapped part of the stack of `hart`
+pub fn stack(hart: usize) -> Range<usize> {
+    let bottom = K_STACK_BEG + hart * SLOT_SIZE + KERNEL_STACK_SIZE;
+    bottom..bottom + KERNEL_STACK_SIZE
+}
+
+/// Whether `addr` lies in the stack of any hart
+pub fn contains(addr: usize) -> bool {
+    (K_STACK_BEG..K_STACK_BEG + CPU_NUM * SLOT_SIZE).contains(&addr)
+        && (addr - K_STACK_BEG) % SLOT_SIZE >= KERNEL_STACK_SIZE
+}
+
+/// Map the stacks of all harts. Must run before any user page table copies
+/// the kernel root table.
+pub fn init() {
+    let mut pt =
+        unsafe { PageTable::from_ppn(kva2pa(VirtAddr(addr_of!(BOOT_PAGE_TABLE) as usize)).into()) };
+    for hart in 0..CPU_NUM {
+        for va in stack(hart).step_by(PAGE_SIZE) {
+            let frame = frame::alloc().expect("failed to allocate kernel stack");
+            frame
+                .page()
+                .set_flags(PageFlags::KERNEL | PageFlags::PINNED);
+            pt.map(
+                VirtAddr(va).floor_page(),
+                frame.ppn,
+                PteFlags::R | PteFlags::W | PteFlags::G | PteFlags::A | PteFlags::D,
+            );
+            // Kernel stacks are never freed
+            core::mem::forget(frame);
+        }
+    }
+    core::mem::forget(pt);
+}
+
+/// Leave the boot stack for the guarded stack of `hart` and run `f` on it
+pub unsafe fn switch_to(hart: usize, f: fn() -> !) -> ! {
+    unsafe {
+        asm!(
+            "mv sp, {sp}",
+            "mv fp, zero",
+            "jr {f}",
+            sp = in(reg) stack(hart).end,
+            f = in(reg) f,
+            options(noreturn)
+        )
+    }
+}
@@ -0,0 +1,132 @@
+use core::ops::Range;
+
+use alloc::{format, string::String, vec::Vec};
+use fdt::Fdt;
+use log::info;
+
+use crate::{device_tree, round_up};
+
+use super::{
+    addr::{PhysAddr, VirtAddr, kva2pa},
+    address_space::PHYSICAL_MEMORY_START,
+    consts::PAGE_SIZE,
+    layout::__kernel_end,
+};
+
+/// Physical memory reported by the device tree and the parts of it which must
+/// not be handed to the frame allocator.
+pub struct MemoryMap {
+    memory: Vec<Range<usize>>,
+    reserved: Vec<(Range<usize>, &'static str)>,
+}
+
+impl MemoryMap {
+    /// `dtb` is the kernel virtual address of the device tree blob
+    pub fn new(device_tree: &Fdt<'static>, dtb: usize) -> Self {
+        let mut memory: Vec<_> = device_tree::memory_regions(device_tree).collect();
+        memory.sort_unstable_by_key(|region| region.start);
+        let mut map = Self {
+            memory,
+            reserved: Vec::new(),
+        };
+        // Firmware is loaded below the kernel
+        let kernel_end = kva2pa(VirtAddr(__kernel_end as usize)).0;
+        map.reserve(PHYSICAL_MEMORY_START..kernel_end, "firmware and kernel");
+        let dtb = kva2pa(VirtAddr(dtb)).0;
+        map.reserve(dtb..dtb + device_tree.total_size(), "device tree");
+        for reservation in device_tree.memory_reservations() {
+            let start = reservation.address() as usize;
+            map.reserve(start..start + reservation.size(), "memory reservation");
+        }
+        if let Some(reserved) = device_tree.find_node("/reserved-memory") {
+            for region in reserved.children().filter_map(|node| node.reg()).flatten() {
+                let start = region.starting_address as usize;
+                let size = region.size.unwrap_or(0);
+                map.reserve(start..start + size, "reserved-memory");
+            }
+        }
+        if let Some(chosen) = device_tree.find_node("/chosen") {
+            let prop = |name| chosen.property(name).and_then(|prop| prop.as_usize());
+            if let (Some(start), Some(end)) = (prop("linux,initrd-start"), prop("linux,initrd-end"))
+            {
+                map.reserve(start..end, "initrd");
+            }
+        }
+        map
+    }
+
+    pub fn reserve(&mut self, range: Range<usize>, name: &'static str) {
+        if !range.is_empty() {
+            self.reserved.push((range, name));
+        }
+    }
+
+    /// End of the highest memory region
+    pub fn end(&self) -> usize {
+        self.memory
+            .iter()
+            .map(|region| region.end)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whole pages of memory which are not reserved, in ascending order
+    pub fn free(&self) -> Vec<Range<usize>> {
+        let mut reserved: Vec<_> = self.reserved.iter().map(|(range, _)| range).collect();
+        reserved.sort_unstable_by_key(|range| range.start);
+        let mut free = Vec::new();
+        for region in &self.memory {
+            let mut start = region.start;
+            for range in &reserved {
+                if range.end <= start || range.start >= region.end {
+                    continue;
+                }
+                if range.start > start {
+                    free.push(start..range.start);
+                }
+                start = start.max(range.end);
+            }
+            if start < region.end {
+                free.push(start..region.end);
+            }
+        }
+        free.into_iter()
+            .map(|range| round_up!(range.start, PAGE_SIZE)..range.end & !(PAGE_SIZE - 1))
+            .filter(|range| range.start < range.end)
+            .collect()
+    }
+
+    /// First free range with room for `size` bytes, which is reserved as `name`
+    pub fn alloc(&mut self, size: usize, name: &'static str) -> Option<PhysAddr> {
+        let size = round_up!(size, PAGE_SIZE);
+        let start = self
+            .free()
+            .into_iter()
+            .find(|range| range.end - range.start >= size)?
+            .start;
+        self.reserve(start..start + size, name);
+        Some(PhysAddr(start))
+    }
+
+    pub fn print(&self) {
+        let mut reserved = self.reserved.clone();
+        reserved.sort_unstable_by_key(|(range, _)| range.start);
+        let mut report = String::from("Physical memory map:");
+        for region in &self.memory {
+            report += &format!("\n  memory   : {:#x} - {:#x}", region.start, region.end);
+        }
+        for (range, name) in &reserved {
+            report += &format!(
+                "\n  reserved : {:#x} - {:#x} {}",
+                range.start, range.end, name
+            );
+        }
+        let free: usize = self
+            .free()
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum();
+        report += &format!("\n  free     : {} KiB", free / 1024);
+        info!("{}", report);
+    }
+}
@@ -2,34 +2,49 @@
 
 use core::ptr::addr_of;
 
-use addr::{PhysAddr, PhysPageNum, VirtAddr, kva2pa};
-use address_space::{K_HARDWARE_BEG, K_HARDWARE_END, PHYSICAL_MEMORY_START};
+use addr::{PhysAddr, PhysPageNum, VirtAddr, kva2pa, pa2kva};
+use address_space::{K_HARDWARE_BEG, K_HARDWARE_END, K_PHYSICAL_MEMORY_BEG, PHYSICAL_MEMORY_START};
+use alloc::vec::Vec;
+use consts::{HUGE_PAGE_SIZE, PAGE_SIZE};
+use fdt::Fdt;
 use log::debug;
-use paging::page_table::PageTable;
+use paging::page_table::{PageSize, PageTable};
 use paging::pte::{PageTableEntry, PteFlags};
 
 use crate::config::MEMORY_SIZE;
 use crate::entry::BOOT_PAGE_TABLE;
+use crate::round_up;
 
 pub mod addr;
 pub mod address_space;
 pub mod consts;
+pub mod file;
 pub mod frame;
-mod heap;
+pub mod heap;
+pub mod kstack;
 pub mod layout;
+pub mod memory_map;
+pub mod page;
 pub mod paging;
+pub mod swap;
+pub mod uaccess;
 
-unsafe extern "C" {
-    fn __kernel_end();
-}
-
-pub fn init() {
+/// `dtb` is the kernel virtual address of the device tree blob
+pub fn init(device_tree: &Fdt<'static>, dtb: usize) {
     heap::init();
     heap::heap_test();
-    frame::init(
-        kva2pa(VirtAddr(__kernel_end as usize)),
-        PhysAddr(PHYSICAL_MEMORY_START + unsafe { MEMORY_SIZE }),
-    );
+    let mut map = memory_map::MemoryMap::new(device_tree, dtb);
+    let memory_end = PhysAddr(map.end());
+    let pages = map
+        .alloc(page::metadata_size(memory_end), "page metadata")
+        .expect("no room for page metadata");
+    let frames = map
+        .alloc(frame::metadata_size(memory_end), "frame allocator")
+        .expect("no room for frame allocator state");
+    page::init(pages, memory_end);
+    frame::init(frames, memory_end, &map.free());
+    map.print();
+    heap::init_growth();
     layout::print_memory_layout();
     // frame::debug_print();
 }
@@ -37,23 +52,103 @@ pub fn init() {
 pub fn map_kernel_regions(dtb: usize) {
     let mut pt =
         unsafe { PageTable::from_ppn(kva2pa(VirtAddr(addr_of!(BOOT_PAGE_TABLE) as usize)).into()) };
+    let memory_end = (PHYSICAL_MEMORY_START + unsafe { MEMORY_SIZE }) & !(PAGE_SIZE - 1);
+    let boot_mapped_end = PHYSICAL_MEMORY_START + HUGE_PAGE_SIZE;
 
     // K_PHYSICAL_MEMORY_BEG - K_PHYSICAL_MEMORY_END (62 GiB)
-    // 0xffff_fff0_4000_0000 - 0xffff_ffff_8000_0000
-    unsafe {
-        for entry in &mut BOOT_PAGE_TABLE[449..511] {
-            *entry = PageTableEntry::new(
-                PhysPageNum(0x80000),
-                PteFlags::R | PteFlags::W | PteFlags::V | PteFlags::X | PteFlags::A | PteFlags::D,
-            );
-        }
+    // 0xffff_fff0_0000_0000 - 0xffff_ffff_8000_0000
+    // The kernel runs from the RWX gigapage of the boot page table. Build the
+    // fine-grained replacement of that gigapage aside and switch to it with a
+    // single store.
+    let mut fine = PageTable::new();
+    map_physical_memory(
+        &mut fine,
+        PHYSICAL_MEMORY_START,
+        usize::min(memory_end, boot_mapped_end),
+    );
+    pt.copy_root_entry(&fine, VirtAddr(K_PHYSICAL_MEMORY_BEG).floor_page());
+    // The new subtree belongs to the kernel page table now
+    core::mem::forget(fine);
+    paging::flush_tlb_all();
+    if memory_end > boot_mapped_end {
+        map_physical_memory(&mut pt, boot_mapped_end, memory_end);
     }
+
     // K_HARDWARE_BEG - K_HARDWARE_END (1GiB but actually 750 MiB)
     // 0xffff_ffff_8000_0000 - 0xffff_ffff_c000_0000
-    unsafe {
-        BOOT_PAGE_TABLE[510] = PageTableEntry::new(
-            PhysAddr(0x0).into(),
-            PteFlags::R | PteFlags::W | PteFlags::V,
-        )
-    };
+    map_devices(&mut pt, dtb);
+    // The boot page table and its subtables live forever
+    core::mem::forget(pt);
+    paging::flush_tlb_all();
+}
+
+/// Map physical memory in `[start, end)` into the linear window. The kernel
+/// image gets the permissions of its sections, everything else is RW.
+fn map_physical_memory(pt: &mut PageTable, start: usize, end: usize) {
+    let global = PteFlags::G | PteFlags::A | PteFlags::D;
+    let rw = PteFlags::R | PteFlags::W | global;
+    let pa = |symbol: unsafe extern "C" fn()| kva2pa(VirtAddr(symbol as usize)).0;
+    let regions = [
+        (PHYSICAL_MEMORY_START, pa(layout::__kernel_start), rw),
+        (
+            pa(layout::__text_start),
+            pa(layout::__text_end),
+            PteFlags::R | PteFlags::X | global,
+        ),
+        (
+            pa(layout::__rodata_start),
+            pa(layout::__rodata_end),
+            PteFlags::R | global,
+        ),
+        (pa(layout::__data_start), pa(layout::__kernel_end), rw),
+        (pa(layout::__kernel_end), usize::MAX, rw),
+    ];
+    for (region_start, region_end, flags) in regions {
+        let region_start = usize::max(region_start, start);
+        let region_end = usize::min(region_end, end);
+        if region_start < region_end {
+            pt.map_range(
+                pa2kva(PhysAddr(region_start)),
+                PhysAddr(region_start),
+                region_end - region_start,
+                flags,
+            );
+        }
+    }
+}
+
+/// Map the MMIO regions of the devices in the device tree at `dtb` into the
+/// hardware window.
+fn map_devices(pt: &mut PageTable, dtb: usize) {
+    let device_tree = unsafe { fdt::Fdt::from_ptr(dtb as *const u8) }.unwrap();
+    let window = K_HARDWARE_END - K_HARDWARE_BEG;
+    let mut regions: Vec<(usize, usize)> = device_tree
+        .all_nodes()
+        .filter_map(|node| node.reg())
+        .flatten()
+        .filter_map(|reg| {
+            let start = reg.starting_address as usize;
+            let end = start.checked_add(reg.size?)?;
+            (start < end && end <= usize::min(window, PHYSICAL_MEMORY_START))
+                .then_some((start & !(PAGE_SIZE - 1), round_up!(end, PAGE_SIZE)))
+        })
+        .collect();
+    regions.sort_unstable();
+    // Devices may share pages
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in regions {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = usize::max(last.1, end),
+            _ => merged.push((start, end)),
+        }
+    }
+    for &(start, end) in &merged {
+        debug!("Mapping device region {:#x} - {:#x}", start, end);
+        pt.map_range(
+            VirtAddr(K_HARDWARE_BEG + start),
+            PhysAddr(start),
+            end - start,
+            PteFlags::R | PteFlags::W | PteFlags::G | PteFlags::A | PteFlags::D,
+        );
+    }
 }
@@ -0,0 +1,190 @@
+use core::{
+    fmt,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use log::info;
+use sync::OnceCell;
+
+use crate::{Mutex, task::pid::Pid};
+
+use super::{
+    addr::{PhysAddr, PhysPageNum, VirtPageNum, pa2kva},
+    address_space::PHYSICAL_MEMORY_START,
+    consts::PAGE_SIZE,
+};
+
+// Metadata of every physical frame, `PAGES[i]` describes the frame at
+// `PHYSICAL_MEMORY_START + i * PAGE_SIZE`
+static PAGES: OnceCell<&'static [Page]> = OnceCell::new();
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFlags: u8 {
+        // Kernel image or kernel metadata
+        const KERNEL = 1 << 0;
+        // Mapped into at least one user address space
+        const USER = 1 << 1;
+        const PAGE_TABLE = 1 << 2;
+        // Must never be reclaimed or moved
+        const PINNED = 1 << 3;
+        // Known to be filled with zeroes
+        const ZEROED = 1 << 4;
+    }
+}
+
+/// One user mapping of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RmapEntry {
+    pub pid: Pid,
+    pub vpn: VirtPageNum,
+}
+
+pub struct Page {
+    // Number of `FrameTracker`s referring to the frame
+    refcount: AtomicUsize,
+    inner: Mutex<PageInner>,
+}
+
+struct PageInner {
+    flags: PageFlags,
+    rmap: Vec<RmapEntry>,
+}
+
+impl Page {
+    const fn new(flags: PageFlags) -> Self {
+        Self {
+            refcount: AtomicUsize::new(0),
+            inner: Mutex::new(PageInner {
+                flags,
+                rmap: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn refcount(&self) -> usize {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    /// Returns the new reference count
+    pub fn inc_ref(&self) -> usize {
+        self.refcount.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Returns the new reference count
+    pub fn dec_ref(&self) -> usize {
+        let old = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        debug_assert!(old > 0, "page reference count underflow");
+        old - 1
+    }
+
+    pub fn flags(&self) -> PageFlags {
+        self.inner.lock().flags
+    }
+
+    pub fn set_flags(&self, flags: PageFlags) {
+        self.inner.lock().flags.insert(flags);
+    }
+
+    pub fn clear_flags(&self, flags: PageFlags) {
+        self.inner.lock().flags.remove(flags);
+    }
+
+    /// Record that `vpn` of task `pid` maps this frame
+    pub fn add_rmap(&self, pid: Pid, vpn: VirtPageNum) {
+        let mut inner = self.inner.lock();
+        inner.flags.insert(PageFlags::USER);
+        inner.rmap.push(RmapEntry { pid, vpn });
+    }
+
+    pub fn remove_rmap(&self, pid: Pid, vpn: VirtPageNum) {
+        let mut inner = self.inner.lock();
+        if let Some(i) = inner.rmap.iter().position(|e| e.pid == pid && e.vpn == vpn) {
+            inner.rmap.swap_remove(i);
+        }
+        if inner.rmap.is_empty() {
+            inner.flags.remove(PageFlags::USER);
+        }
+    }
+
+    /// Number of user mappings of the frame
+    pub fn map_count(&self) -> usize {
+        self.inner.lock().rmap.len()
+    }
+
+    /// Snapshot of the user mappings of the frame
+    pub fn rmap(&self) -> Vec<RmapEntry> {
+        self.inner.lock().rmap.clone()
+    }
+
+    /// Forget everything about the frame once it is freed
+    pub(super) fn reset(&self) {
+        let mut inner = self.inner.lock();
+        debug_assert!(inner.rmap.is_empty(), "freeing a frame still mapped");
+        inner.flags = PageFlags::empty();
+        inner.rmap = Vec::new();
+    }
+}
+
+impl fmt::Debug for Page {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.lock();
+        f.debug_struct("Page")
+            .field("refcount", &self.refcount())
+            .field("flags", &inner.flags)
+            .field("rmap", &inner.rmap)
+            .finish()
+    }
+}
+
+fn frames(end: PhysAddr) -> usize {
+    end.floor_page().0 - PhysPageNum::from(PhysAddr(PHYSICAL_MEMORY_START)).0
+}
+
+/// Bytes of metadata needed for the frames below `end`
+pub fn metadata_size(end: PhysAddr) -> usize {
+    frames(end) * size_of::<Page>()
+}
+
+/// Place the metadata of the frames below `end` at `start`. Every frame is
+/// marked as used by the kernel until the frame allocator takes it.
+pub fn init(start: PhysAddr, end: PhysAddr) {
+    let frames = frames(end);
+    let pages = unsafe {
+        let ptr = pa2kva(start).as_mut_ptr::<Page>();
+        for i in 0..frames {
+            ptr.add(i)
+                .write(Page::new(PageFlags::KERNEL | PageFlags::PINNED));
+        }
+        core::slice::from_raw_parts(ptr, frames)
+    };
+    PAGES
+        .initialize(|| pages)
+        .expect("page metadata initialized twice");
+    info!(
+        "Initialized metadata of {} pages using {} KiB.",
+        frames,
+        metadata_size(end) / 1024
+    );
+}
+
+/// Metadata of the frame `ppn`
+pub fn lookup(ppn: PhysPageNum) -> &'static Page {
+    let pages = PAGES.get().expect("page metadata not initialized");
+    let base = PhysPageNum::from(PhysAddr(PHYSICAL_MEMORY_START));
+    &pages[ppn.0 - base.0]
+}
+
+/// Physical page numbers which have metadata
+pub fn ppn_range() -> Range<usize> {
+    let pages = PAGES.get().expect("page metadata not initialized");
+    let base = PhysPageNum::from(PhysAddr(PHYSICAL_MEMORY_START));
+    base.0..base.0 + pages.len()
+}
+
+pub fn debug_print(ppn: PhysPageNum) {
+    log::debug!("{}: {:?}", ppn, lookup(ppn));
+}
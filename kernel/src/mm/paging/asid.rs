@@ -0,0 +1,124 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use arch::tp;
+use log::info;
+use riscv::register::satp::{self, Mode};
+
+use crate::{Mutex, config::CPU_NUM, mask, mm::addr::PhysPageNum};
+
+// Sv39 allows at most 16 ASID bits
+const MAX_ASID_BITS: usize = 16;
+
+static ASID_BITS: AtomicUsize = AtomicUsize::new(0);
+
+static ASID_ALLOCATOR: Mutex<AsidAllocator> = Mutex::new(AsidAllocator::new());
+
+// Current ASID generation, only changed with `ASID_ALLOCATOR` held so that
+// switching address spaces can check it without taking the lock
+static GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+// Set on every hart when the ASID generation rolls over, the hart has to
+// flush its whole TLB before it runs with an ASID of the new generation.
+static FLUSH_PENDING: [AtomicBool; CPU_NUM] = [const { AtomicBool::new(false) }; CPU_NUM];
+
+/// ASID of an address space, only valid while its generation is current.
+#[derive(Debug, Clone, Copy)]
+pub struct Asid {
+    generation: usize,
+    value: usize,
+}
+
+impl Asid {
+    /// An ASID which is never valid, it gets one assigned on its first activation
+    pub const fn new() -> Self {
+        Self {
+            generation: 0,
+            value: 0,
+        }
+    }
+
+    pub fn value(&self) -> usize {
+        self.value
+    }
+}
+
+impl Default for Asid {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct AsidAllocator {
+    next: usize,
+}
+
+impl AsidAllocator {
+    const fn new() -> Self {
+        Self { next: 1 }
+    }
+
+    fn alloc(&mut self) -> Asid {
+        // ASID 0 is used by the kernel and never handed out
+        if self.next >= 1 << asid_bits() {
+            GENERATION.fetch_add(1, Ordering::SeqCst);
+            self.next = 1;
+            FLUSH_PENDING
+                .iter()
+                .for_each(|flag| flag.store(true, Ordering::SeqCst));
+        }
+        let asid = Asid {
+            generation: GENERATION.load(Ordering::Relaxed),
+            value: self.next,
+        };
+        self.next += 1;
+        asid
+    }
+}
+
+/// Detect how many ASID bits the hardware implements by writing all ones to
+/// satp.ASID and reading back.
+pub fn init() {
+    let old = satp::read();
+    let bits = unsafe {
+        satp::set(Mode::Sv39, mask!(MAX_ASID_BITS), old.ppn());
+        let bits = satp::read().asid().count_ones() as usize;
+        satp::set(Mode::Sv39, old.asid(), old.ppn());
+        bits
+    };
+    ASID_BITS.store(bits, Ordering::Release);
+    info!("{bits} ASID bits supported.");
+}
+
+pub fn asid_bits() -> usize {
+    ASID_BITS.load(Ordering::Acquire)
+}
+
+/// Switch to the page table `pt` tagged with `asid`, allocating a new ASID if
+/// it belongs to an old generation.
+///
+/// Returns true if no other hart can hold translations tagged with `asid`,
+/// i.e. a new ASID was allocated or the whole TLB is flushed on every switch.
+pub fn activate(pt: PhysPageNum, asid: &mut Asid) -> bool {
+    if asid_bits() == 0 {
+        // No ASID support, fall back to flushing everything
+        super::switch_page_table(pt, 0, true);
+        return true;
+    }
+    let mut fresh = false;
+    loop {
+        // The lock is only taken on the first activation and after a rollover
+        if asid.generation != GENERATION.load(Ordering::SeqCst) {
+            let mut allocator = ASID_ALLOCATOR.lock();
+            if asid.generation != GENERATION.load(Ordering::Relaxed) {
+                *asid = allocator.alloc();
+                fresh = true;
+            }
+        }
+        let flush = FLUSH_PENDING[tp()].swap(false, Ordering::SeqCst);
+        super::switch_page_table(pt, asid.value, flush);
+        // A rollover since the check may have handed the ASID out again
+        if asid.generation == GENERATION.load(Ordering::SeqCst) {
+            return fresh;
+        }
+    }
+}
@@ -5,21 +5,26 @@ use crate::entry::BOOT_PAGE_TABLE;
 use super::addr::PhysPageNum;
 use super::consts::PAGE_SIZE;
 
+pub mod asid;
 pub mod page_table;
 pub mod pte;
+pub mod tlb;
 
+/// Write satp, flushing the whole TLB if `flush` is set. Entries of other
+/// address spaces are kept as long as they are tagged with a different ASID.
 #[inline]
-pub fn switch_page_table(pt: PhysPageNum) -> PhysPageNum {
-    let old_pt = riscv::register::satp::read().ppn();
-    if old_pt == pt.0 {
-        return PhysPageNum(old_pt);
+pub fn switch_page_table(pt: PhysPageNum, asid: usize, flush: bool) -> PhysPageNum {
+    let old = riscv::register::satp::read();
+    if old.ppn() != pt.0 || old.asid() != asid {
+        unsafe {
+            riscv::register::satp::set(riscv::register::satp::Mode::Sv39, asid, pt.0);
+        }
+        // debug!("Switched page table to 0x{:x}", pt.0 * PAGE_SIZE);
     }
-    unsafe {
-        riscv::register::satp::set(riscv::register::satp::Mode::Sv39, 0, pt.0);
-        riscv::asm::sfence_vma_all();
+    if flush {
+        flush_tlb_all();
     }
-    // debug!("Switched page table to 0x{:x}", pt.0 * PAGE_SIZE);
-    PhysPageNum(old_pt)
+    PhysPageNum(old.ppn())
 }
 
 pub fn flush_tlb(vaddr: usize) {
@@ -30,6 +35,16 @@ pub fn flush_tlb_all() {
     unsafe { riscv::asm::sfence_vma_all() };
 }
 
+/// Flush the translation of `vaddr` in the address space tagged with `asid`
+pub fn flush_tlb_asid(asid: usize, vaddr: usize) {
+    unsafe { riscv::asm::sfence_vma(asid, vaddr) };
+}
+
+/// Flush all non-global translations of the address space tagged with `asid`
+pub fn flush_tlb_asid_all(asid: usize) {
+    unsafe { core::arch::asm!("sfence.vma zero, {0}", in(reg) asid) };
+}
+
 pub fn unmap_low_memory() {
     unsafe {
         BOOT_PAGE_TABLE[..256].fill(pte::PageTableEntry::EMPTY);
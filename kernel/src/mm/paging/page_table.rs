@@ -9,8 +9,11 @@ use crate::entry::BOOT_PAGE_TABLE;
 use crate::mask;
 use crate::mm::addr::{PhysAddr, PhysPageNum, VirtPageNum, pa2kva};
 use crate::mm::addr::{VirtAddr, kva2pa};
-use crate::mm::consts::{PAGE_TABLE_ENTRY_COUNT as ENTRY_COUNT, PPN_WIDTH};
+use crate::mm::consts::{
+    HUGE_PAGE_SIZE, MEGA_PAGE_SIZE, PAGE_SIZE, PAGE_TABLE_ENTRY_COUNT as ENTRY_COUNT, PPN_WIDTH,
+};
 use crate::mm::frame::{self, FrameTracker};
+use crate::mm::page::PageFlags;
 
 impl PhysPageNum {
     fn as_page_table(&self) -> &'static mut [PageTableEntry] {
@@ -31,6 +34,56 @@ impl VirtPageNum {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// 4 KiB page, mapped at the last level
+    Size4KiB,
+    /// 2 MiB megapage, mapped at the middle level
+    Size2MiB,
+    /// 1 GiB gigapage, mapped at the root level
+    Size1GiB,
+}
+
+impl PageSize {
+    /// The level of the page table walk the leaf entry lives in
+    pub const fn level(self) -> usize {
+        match self {
+            PageSize::Size1GiB => 0,
+            PageSize::Size2MiB => 1,
+            PageSize::Size4KiB => 2,
+        }
+    }
+
+    const fn from_level(level: usize) -> Self {
+        match level {
+            0 => PageSize::Size1GiB,
+            1 => PageSize::Size2MiB,
+            _ => PageSize::Size4KiB,
+        }
+    }
+
+    pub const fn bytes(self) -> usize {
+        match self {
+            PageSize::Size4KiB => PAGE_SIZE,
+            PageSize::Size2MiB => MEGA_PAGE_SIZE,
+            PageSize::Size1GiB => HUGE_PAGE_SIZE,
+        }
+    }
+
+    /// Number of 4 KiB pages covered
+    pub const fn pages(self) -> usize {
+        self.bytes() / PAGE_SIZE
+    }
+}
+
+fn alloc_table_frame() -> FrameTracker {
+    let frame = frame::alloc().expect("failed to allocate frame for page table");
+    let page = frame.page();
+    page.set_flags(PageFlags::PAGE_TABLE | PageFlags::PINNED);
+    page.clear_flags(PageFlags::ZEROED);
+    frame
+}
+
 #[derive(Debug)]
 pub struct PageTable {
     ppn: PhysPageNum,
@@ -39,7 +92,7 @@ pub struct PageTable {
 
 impl PageTable {
     pub fn new() -> Self {
-        let frame = frame::alloc().expect("failed to allocate frame for page table");
+        let frame = alloc_table_frame();
         Self {
             ppn: frame.ppn,
             frames: vec![frame],
@@ -72,56 +125,162 @@ impl PageTable {
         self.ppn
     }
 
-    pub fn find(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    /// Find the leaf entry mapping `vpn`, which may be a superpage.
+    pub fn find(&self, vpn: VirtPageNum) -> Option<(&mut PageTableEntry, PageSize)> {
         let indices = vpn.indices();
         let mut page_table = self.ppn;
-        let mut result = None;
-        for (i, index) in indices.iter().enumerate() {
+        for (level, index) in indices.iter().enumerate() {
             let pte = &mut page_table.as_page_table()[*index];
             if !pte.valid() {
                 return None;
             }
-            if i == 2 {
-                result = Some(pte);
-                return result;
+            if level == 2 || pte.is_leaf() {
+                return Some((pte, PageSize::from_level(level)));
             }
             page_table = pte.ppn();
         }
-        result
+        unreachable!()
     }
 
-    pub fn find_create(&mut self, vpn: VirtPageNum) -> &mut PageTableEntry {
+    /// Walk down to the entry of `size` for `vpn`, creating intermediate tables
+    /// and splitting any superpage found on the way.
+    pub fn find_create(&mut self, vpn: VirtPageNum, size: PageSize) -> &mut PageTableEntry {
         let indices = vpn.indices();
         let mut page_table = self.ppn;
-        for (i, index) in indices.iter().enumerate() {
+        for (level, index) in indices.iter().enumerate() {
             let pte = &mut page_table.as_page_table()[*index];
-            if i == 2 {
+            if level == size.level() {
                 return pte;
             }
             if !pte.valid() {
-                let frame = frame::alloc().expect("failed to allocate frame for page table");
+                let frame = alloc_table_frame();
                 *pte = PageTableEntry::new(frame.ppn, PteFlags::V);
                 self.frames.push(frame);
+            } else if pte.is_leaf() {
+                self.split(pte, PageSize::from_level(level));
             }
             page_table = pte.ppn();
         }
         unreachable!()
     }
 
-    // TODO: Support 2MiB and 1GiB pages
+    /// Replace a superpage by a table of next-level pages with the same
+    /// translation and flags.
+    fn split(&mut self, pte: &mut PageTableEntry, size: PageSize) {
+        debug_assert!(pte.is_leaf() && size != PageSize::Size4KiB);
+        let child = PageSize::from_level(size.level() + 1);
+        let frame = alloc_table_frame();
+        for (i, entry) in frame.ppn.as_page_table().iter_mut().enumerate() {
+            *entry = PageTableEntry::new(pte.ppn() + i * child.pages(), pte.flags());
+        }
+        *pte = PageTableEntry::new(frame.ppn, PteFlags::V);
+        self.frames.push(frame);
+    }
+
     pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PteFlags) {
-        let pte = self.find_create(vpn);
+        self.map_huge(vpn, ppn, flags, PageSize::Size4KiB);
+    }
+
+    pub fn map_huge(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: PteFlags,
+        size: PageSize,
+    ) {
+        debug_assert!(vpn.0.is_multiple_of(size.pages()) && ppn.0.is_multiple_of(size.pages()));
+        let pte = self.find_create(vpn, size);
         debug_assert!(!pte.valid());
         *pte = PageTableEntry::new(ppn, flags | PteFlags::V);
     }
 
+    /// Map a physically contiguous range with the largest pages its alignment allows.
+    pub fn map_range(&mut self, va: VirtAddr, pa: PhysAddr, len: usize, flags: PteFlags) {
+        debug_assert!(
+            va.0.is_multiple_of(PAGE_SIZE)
+                && pa.0.is_multiple_of(PAGE_SIZE)
+                && len.is_multiple_of(PAGE_SIZE)
+        );
+        let mut offset = 0;
+        while offset < len {
+            let size = [PageSize::Size1GiB, PageSize::Size2MiB, PageSize::Size4KiB]
+                .into_iter()
+                .find(|size| {
+                    (va.0 + offset).is_multiple_of(size.bytes())
+                        && (pa.0 + offset).is_multiple_of(size.bytes())
+                        && len - offset >= size.bytes()
+                })
+                .unwrap();
+            self.map_huge(
+                (va + offset).floor_page(),
+                (pa + offset).floor_page(),
+                flags,
+                size,
+            );
+            offset += size.bytes();
+        }
+    }
+
+    /// Make the root entry covering `vpn` the same as in `other`, so both share
+    /// its subtree. A single store, so it can replace a live mapping.
+    pub fn copy_root_entry(&mut self, other: &PageTable, vpn: VirtPageNum) {
+        let index = vpn.indices()[0];
+        self.ppn.as_page_table()[index] = other.ppn.as_page_table()[index];
+    }
+
+    /// Unmap a single page. A superpage covering it is split first, so the
+    /// rest of it stays mapped.
     pub fn unmap(&mut self, vpn: VirtPageNum) {
-        let pte = self.find(vpn).expect("failed to unmap page");
+        let pte = match self.find(vpn).expect("failed to unmap page") {
+            (pte, PageSize::Size4KiB) => pte,
+            _ => self.find_create(vpn, PageSize::Size4KiB),
+        };
         debug_assert!(pte.valid());
         pte.clear();
     }
 
+    /// Unmap a whole superpage of `size` starting at `vpn`.
+    pub fn unmap_huge(&mut self, vpn: VirtPageNum, size: PageSize) {
+        let (pte, found) = self.find(vpn).expect("failed to unmap page");
+        debug_assert!(found == size && vpn.0.is_multiple_of(size.pages()));
+        pte.clear();
+    }
+
+    /// Replace the translation of `vpn` by a swap entry. The page must be mapped
+    /// by a 4 KiB entry.
+    pub fn set_swap(&mut self, vpn: VirtPageNum, slot: usize) {
+        let (pte, size) = self.find(vpn).expect("failed to swap out page");
+        debug_assert!(size == PageSize::Size4KiB);
+        *pte = PageTableEntry::new_swap(slot);
+    }
+
+    /// The swap slot recorded for `vpn`, if it is swapped out
+    pub fn swap_slot(&self, vpn: VirtPageNum) -> Option<usize> {
+        let indices = vpn.indices();
+        let mut page_table = self.ppn;
+        for index in &indices[..2] {
+            let pte = &page_table.as_page_table()[*index];
+            if !pte.valid() || pte.is_leaf() {
+                return None;
+            }
+            page_table = pte.ppn();
+        }
+        page_table.as_page_table()[indices[2]].swap_slot()
+    }
+
+    /// Forget the swap entry of `vpn`
+    pub fn clear_swap(&mut self, vpn: VirtPageNum) {
+        if self.swap_slot(vpn).is_some() {
+            self.find_create(vpn, PageSize::Size4KiB).clear();
+        }
+    }
+
+    /// Query the translation of a single 4 KiB page, resolving superpages.
     pub fn query(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        Some(*self.find(vpn)?)
+        let (pte, size) = self.find(vpn)?;
+        Some(PageTableEntry::new(
+            pte.ppn() + (vpn.0 & (size.pages() - 1)),
+            pte.flags(),
+        ))
     }
 }
@@ -31,6 +31,8 @@ bitflags! {
         const COW = 1 << 8;
         // Reserved for software
         const RSW2 = 1 << 9;
+        // Swapped out, set in an invalid entry whose PPN field holds the swap slot
+        const SWAP = 1 << 9;
     }
 }
 
@@ -49,6 +51,30 @@ impl PageTableEntry {
 
     pub const EMPTY: Self = Self { bits: 0 };
 
+    /// An invalid entry recording that the page lives in swap `slot`
+    pub const fn new_swap(slot: usize) -> Self {
+        Self {
+            bits: (slot << 10) | PteFlags::SWAP.bits() as usize,
+        }
+    }
+
+    /// The swap slot of a swapped out page
+    pub fn swap_slot(&self) -> Option<usize> {
+        if !self.valid() && self.flags().contains(PteFlags::SWAP) {
+            Some(self.ppn().0)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_flags(&mut self, flags: PteFlags) {
+        self.bits |= flags.bits() as usize;
+    }
+
+    pub fn clear_flags(&mut self, flags: PteFlags) {
+        self.bits &= !(flags.bits() as usize);
+    }
+
     pub fn clear(&mut self) {
         self.bits = 0;
     }
@@ -96,6 +122,14 @@ impl PageTableEntry {
     pub fn global(&self) -> bool {
         self.flags().contains(PteFlags::G)
     }
+
+    /// A valid entry with any of R/W/X set is a leaf, otherwise it points to the next level
+    pub fn is_leaf(&self) -> bool {
+        self.valid()
+            && self
+                .flags()
+                .intersects(PteFlags::R | PteFlags::W | PteFlags::X)
+    }
 }
 
 impl fmt::Debug for PageTableEntry {
@@ -0,0 +1,57 @@
+use arch::tp;
+use log::warn;
+use sbi::rfence::{sbi_remote_sfence_vma, sbi_remote_sfence_vma_asid};
+
+use crate::mm::consts::PAGE_SIZE;
+
+use super::{asid::asid_bits, flush_tlb_asid, flush_tlb_asid_all};
+
+/// Flush `[vaddr, vaddr + size)` of the address space tagged with `asid` on
+/// every hart in the `harts` bitmask, `size == usize::MAX` flushes the whole
+/// address space.
+///
+/// Remote harts are reached through the SBI RFENCE extension, which returns
+/// only after they have executed the fence.
+pub fn shootdown(harts: usize, asid: usize, vaddr: usize, size: usize) {
+    let this = 1 << tp();
+    if harts & this != 0 {
+        if size == usize::MAX {
+            flush_tlb_asid_all(asid);
+        } else {
+            (vaddr..vaddr + size)
+                .step_by(PAGE_SIZE)
+                .for_each(|va| flush_tlb_asid(asid, va));
+        }
+    }
+    let remote = (harts & !this) as u64;
+    if remote == 0 {
+        return;
+    }
+    let (start, size) = if size == usize::MAX {
+        (0, u64::MAX)
+    } else {
+        (vaddr as u64, size as u64)
+    };
+    let ret = if asid_bits() == 0 {
+        sbi_remote_sfence_vma(remote, 0, start, size)
+    } else {
+        sbi_remote_sfence_vma_asid(remote, 0, start, size, asid as u64)
+    };
+    if !ret.is_success() {
+        warn!(
+            "Remote sfence.vma on harts {:#b} failed: {:?}",
+            remote, ret.error
+        );
+    }
+}
+
+/// Flush global kernel translations of `[vaddr, vaddr + size)` on all harts
+pub fn shootdown_kernel(vaddr: usize, size: usize) {
+    let ret = sbi_remote_sfence_vma(0, u64::MAX, vaddr as u64, size as u64);
+    if !ret.is_success() {
+        // Better than nothing, other harts may still see the old mappings
+        (vaddr..vaddr + size)
+            .step_by(PAGE_SIZE)
+            .for_each(super::flush_tlb);
+    }
+}
@@ -0,0 +1,299 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use log::{info, warn};
+
+use crate::{
+    Mutex,
+    config::SWAP_SIZE,
+    drivers::block::{self, BLOCK_SIZE, MBR_TYPE_SWAP},
+    error::OsError,
+    task::{
+        pid::Pid,
+        schedule,
+        user_space::{UserPageFaultError, UserSpace},
+    },
+};
+
+use super::{
+    addr::{PhysAddr, PhysPageNum},
+    consts::PAGE_SIZE,
+    file,
+    frame::FrameTracker,
+    page::{self, PageFlags, RmapEntry},
+};
+
+const BLOCKS_PER_SLOT: usize = PAGE_SIZE / BLOCK_SIZE;
+
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+
+// Next frame examined by the clock algorithm, relative to the first frame
+static CLOCK_HAND: AtomicUsize = AtomicUsize::new(0);
+
+struct SwapArea {
+    first_block: usize,
+    // Number of page table entries referring to each slot, plus one while it
+    // is in the swap cache, 0 if free
+    refs: Vec<u16>,
+    free: usize,
+    next: usize,
+    // Frames holding the contents of slots, see `CacheState`
+    cache: BTreeMap<usize, Cached>,
+}
+
+struct Cached {
+    frame: Arc<FrameTracker>,
+    state: CacheState,
+}
+
+/// Swap I/O is not done where pages are swapped in or out, as the address
+/// space is locked there. The page goes through the swap cache instead and
+/// `run_io` does the I/O later, with no address space locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CacheState {
+    /// To be read from the slot
+    Wanted,
+    Reading,
+    /// Same as the slot
+    Clean,
+    /// To be written to the slot
+    Dirty,
+    Writing,
+}
+
+impl SwapArea {
+    fn put(&mut self, slot: usize) {
+        debug_assert!(self.refs[slot] > 0);
+        self.refs[slot] -= 1;
+        if self.refs[slot] == 0 {
+            self.free += 1;
+        }
+    }
+
+    /// Drop the cached frame of `slot` if no page table entry refers to the
+    /// slot anymore and no I/O is in flight on it
+    fn trim(&mut self, slot: usize) {
+        let idle = self.cache.get(&slot).is_some_and(|cached| {
+            !matches!(cached.state, CacheState::Reading | CacheState::Writing)
+        });
+        if idle && self.refs[slot] == 1 {
+            self.cache.remove(&slot);
+            self.put(slot);
+        }
+    }
+}
+
+/// Use the swap partition of the block device as swap space, at most
+/// `SWAP_SIZE` bytes of it. Without one the disk is left alone and pages are
+/// never reclaimed, `make disk` creates an image with one.
+pub fn init() {
+    let Some(partition) = block::partitions()
+        .into_iter()
+        .find(|partition| partition.ty == MBR_TYPE_SWAP)
+    else {
+        warn!("No swap partition (MBR type 0x82) found, swap disabled.");
+        return;
+    };
+    let first_block = partition.first_block;
+    let blocks = partition.blocks.min(SWAP_SIZE / BLOCK_SIZE);
+    let slots = blocks / BLOCKS_PER_SLOT;
+    if slots == 0 {
+        warn!("Swap partition too small.");
+        return;
+    }
+    *SWAP.lock() = Some(SwapArea {
+        first_block,
+        refs: vec![0; slots],
+        free: slots,
+        next: 0,
+        cache: BTreeMap::new(),
+    });
+    info!(
+        "Initialized {} KiB of swap at block {}.",
+        slots * PAGE_SIZE / 1024,
+        first_block
+    );
+}
+
+pub fn alloc_slot() -> Option<usize> {
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut()?;
+    if swap.free == 0 {
+        return None;
+    }
+    let slots = swap.refs.len();
+    let slot = (0..slots)
+        .map(|i| (swap.next + i) % slots)
+        .find(|&slot| swap.refs[slot] == 0)?;
+    swap.refs[slot] = 1;
+    swap.free -= 1;
+    swap.next = (slot + 1) % slots;
+    Some(slot)
+}
+
+/// Another page table entry refers to `slot`
+pub fn dup_slot(slot: usize) {
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().expect("swap not initialized");
+    debug_assert!(swap.refs[slot] > 0);
+    swap.refs[slot] += 1;
+}
+
+pub fn free_slot(slot: usize) {
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().expect("swap not initialized");
+    swap.put(slot);
+    swap.trim(slot);
+}
+
+/// Queue `frame`, just replaced by `slot` in a page table, to be written to
+/// the slot. The frame is released once it is written.
+pub fn swap_out(slot: usize, frame: Arc<FrameTracker>) {
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().expect("swap not initialized");
+    swap.refs[slot] += 1;
+    let state = CacheState::Dirty;
+    swap.cache.insert(slot, Cached { frame, state });
+}
+
+/// The frame holding the contents of `slot`. If it is not cached, a frame
+/// from `alloc` is queued to be read into and `SwapIo` returned.
+pub fn swap_in(
+    slot: usize,
+    alloc: impl FnOnce() -> Result<FrameTracker, UserPageFaultError>,
+) -> Result<Arc<FrameTracker>, UserPageFaultError> {
+    let cached = |swap: &mut SwapArea| {
+        swap.cache.get(&slot).map(|cached| match cached.state {
+            CacheState::Wanted | CacheState::Reading => Err(UserPageFaultError::SwapIo),
+            _ => Ok(cached.frame.clone()),
+        })
+    };
+    if let Some(result) = SWAP.lock().as_mut().and_then(cached) {
+        return result;
+    }
+    // Allocating may swap pages out, so the swap area is not locked
+    let frame = Arc::new(alloc()?);
+    let mut swap = SWAP.lock();
+    let swap = swap.as_mut().expect("swap not initialized");
+    if let Some(result) = cached(swap) {
+        return result;
+    }
+    swap.refs[slot] += 1;
+    let state = CacheState::Wanted;
+    swap.cache.insert(slot, Cached { frame, state });
+    Err(UserPageFaultError::SwapIo)
+}
+
+/// Do the I/O queued in the swap cache and the page cache. Must be called
+/// with no address space locked.
+pub fn run_io() -> Result<(), OsError> {
+    file::run_io()?;
+    loop {
+        let (slot, block, frame, state) =
+            {
+                let mut swap = SWAP.lock();
+                let Some(swap) = swap.as_mut() else {
+                    return Ok(());
+                };
+                let first_block = swap.first_block;
+                let Some((&slot, cached)) = swap.cache.iter_mut().find(|(_, cached)| {
+                    matches!(cached.state, CacheState::Wanted | CacheState::Dirty)
+                }) else {
+                    return Ok(());
+                };
+                cached.state = match cached.state {
+                    CacheState::Wanted => CacheState::Reading,
+                    _ => CacheState::Writing,
+                };
+                let block = first_block + slot * BLOCKS_PER_SLOT;
+                (slot, block, cached.frame.clone(), cached.state)
+            };
+        let result = if state == CacheState::Reading {
+            let buf = unsafe { PhysAddr::from(frame.ppn).as_mut_page_slice() };
+            frame.page().clear_flags(PageFlags::ZEROED);
+            block::with_device(|dev| dev.read_blocks(block, buf))
+        } else {
+            let buf = unsafe { PhysAddr::from(frame.ppn).as_page_slice() };
+            block::with_device(|dev| dev.write_blocks(block, buf))
+        };
+        drop(frame);
+        let mut swap = SWAP.lock();
+        let swap = swap.as_mut().unwrap();
+        let cached = swap.cache.get_mut(&slot).unwrap();
+        match (state, result) {
+            (CacheState::Reading, Ok(())) => {
+                cached.state = CacheState::Clean;
+                swap.trim(slot);
+            }
+            (CacheState::Writing, Ok(())) => {
+                // Swapped in meanwhile or not, the slot now holds the page
+                swap.cache.remove(&slot);
+                swap.put(slot);
+            }
+            (CacheState::Reading, Err(e)) => {
+                swap.cache.remove(&slot);
+                swap.put(slot);
+                return Err(e);
+            }
+            (_, Err(e)) => {
+                cached.state = CacheState::Dirty;
+                return Err(e);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Run `f` on the address space of `pid`. `current` is already locked by the
+/// caller, other spaces are skipped if they are busy.
+fn with_space(current: &mut UserSpace, pid: Pid, f: impl FnOnce(&mut UserSpace) -> bool) -> bool {
+    if current.pid() == pid {
+        return f(current);
+    }
+    let Some(task) = schedule::get_task(pid) else {
+        return false;
+    };
+    match task.memory().try_lock() {
+        Some(mut space) => f(&mut space),
+        None => false,
+    }
+}
+
+/// Free up to `count` frames, returns how many were freed or queued to be
+/// freed by `run_io`. Clean pages of the page cache no one maps are freed
+/// right away, the other user pages are evicted by `UserSpace::try_evict`.
+///
+/// Victims are picked by the clock algorithm over the PTE accessed and dirty
+/// bits: the first sweep only takes pages which are neither accessed nor
+/// dirty, the second takes dirty ones too and clears the accessed bit of
+/// every page it passes. Pinned pages are left alone, and so are pages
+/// mapped by more than one address space unless they are in the page cache.
+pub fn reclaim(current: &mut UserSpace, count: usize) -> usize {
+    let mut reclaimed = file::shrink();
+    if reclaimed >= count {
+        return reclaimed;
+    }
+    let frames = page::ppn_range();
+    for pass in 0..4 {
+        let second_chance = pass % 2 == 1;
+        for _ in 0..frames.len() {
+            let ppn = frames.start + CLOCK_HAND.fetch_add(1, Ordering::Relaxed) % frames.len();
+            let page = page::lookup(PhysPageNum(ppn));
+            let flags = page.flags();
+            if !flags.contains(PageFlags::USER) || flags.contains(PageFlags::PINNED) {
+                continue;
+            }
+            let mut evicted = false;
+            for RmapEntry { pid, vpn } in page.rmap() {
+                evicted |= with_space(current, pid, |space| space.try_evict(vpn, second_chance));
+            }
+            if evicted {
+                reclaimed += 1;
+                if reclaimed >= count {
+                    return reclaimed;
+                }
+            }
+        }
+    }
+    reclaimed
+}
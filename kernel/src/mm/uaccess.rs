@@ -0,0 +1,254 @@
+//! Access to user memory from the kernel. Ranges are checked up front, pages
+//! are faulted in through the `UserSpace` and any fault taken anyway ends the
+//! copy through the exception table instead of panicking.
+
+use core::{arch::global_asm, marker::PhantomData, mem::MaybeUninit};
+
+use alloc::vec::Vec;
+
+use crate::{
+    Mutex,
+    error::OsError,
+    task::user_space::{UserPageFaultError, UserPageFaultType, UserSpace},
+};
+
+use super::{addr::VirtAddr, address_space::is_illegal_user_va_range, consts::PAGE_SIZE, swap};
+
+global_asm!(
+    "
+    .section .text.uaccess, \"ax\"
+    .p2align 2
+    .globl __copy_user
+// Copy a2 bytes from a1 to a0, returns the number of bytes left uncopied
+__copy_user:
+    beqz a2, 2f
+0:  lb t0, 0(a1)
+1:  sb t0, 0(a0)
+    addi a0, a0, 1
+    addi a1, a1, 1
+    addi a2, a2, -1
+    bnez a2, 0b
+2:  mv a0, a2
+    ret
+    .pushsection __ex_table, \"a\"
+    .balign 8
+    .dword 0b, 2b
+    .dword 1b, 2b
+    .popsection
+
+    .p2align 2
+    .globl __strncpy_user
+// Copy up to a2 bytes of the string at a1 to a0, including the terminating
+// NUL. Returns the length of the string copied without the NUL, a2 if it was
+// not terminated, or -1 on a fault.
+__strncpy_user:
+    mv t1, a0
+    beqz a2, 4f
+3:  lb t0, 0(a1)
+    sb t0, 0(a0)
+    beqz t0, 4f
+    addi a0, a0, 1
+    addi a1, a1, 1
+    addi a2, a2, -1
+    bnez a2, 3b
+4:  sub a0, a0, t1
+    ret
+5:  li a0, -1
+    ret
+    .pushsection __ex_table, \"a\"
+    .balign 8
+    .dword 3b, 5b
+    .popsection
+    "
+);
+
+unsafe extern "C" {
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __strncpy_user(dst: *mut u8, src: *const u8, len: usize) -> isize;
+}
+
+/// Run `f` with user pages accessible to the kernel
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    unsafe { riscv::register::sstatus::set_sum() };
+    let result = f();
+    unsafe { riscv::register::sstatus::clear_sum() };
+    result
+}
+
+/// A range of user memory
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: usize, len: usize) -> Result<Self, OsError> {
+        if is_illegal_user_va_range(addr, len) {
+            return Err(OsError::InvalidParam);
+        }
+        Ok(UserSlice { addr, len })
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Call `copy` with the offset and length of each piece of the first
+    /// `len` bytes which lies in one page, after faulting that page in. The
+    /// space stays locked while copying, so the page cannot be swapped out
+    /// meanwhile. Stops early when `copy` returns false.
+    fn for_each_page(
+        &self,
+        memory: &Mutex<UserSpace>,
+        len: usize,
+        ty: UserPageFaultType,
+        mut copy: impl FnMut(usize, usize) -> Result<bool, OsError>,
+    ) -> Result<(), OsError> {
+        let mut space = memory.lock();
+        let mut offset = 0;
+        while offset < len {
+            let va = self.addr + offset;
+            let piece = usize::min(PAGE_SIZE - va % PAGE_SIZE, len - offset);
+            match space.fault_in(VirtAddr(va), ty) {
+                Ok(()) => {}
+                Err(UserPageFaultError::SwapIo) => {
+                    drop(space);
+                    swap::run_io()?;
+                    space = memory.lock();
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            if !copy(offset, piece)? {
+                break;
+            }
+            offset += piece;
+        }
+        Ok(())
+    }
+
+    /// Copy the whole range into the start of `dst`, which must be at least
+    /// as long as the range
+    pub fn copy_from_user(&self, memory: &Mutex<UserSpace>, dst: &mut [u8]) -> Result<(), OsError> {
+        let dst = dst.get_mut(..self.len).ok_or(OsError::InvalidParam)?;
+        self.for_each_page(memory, self.len, UserPageFaultType::Read, |offset, len| {
+            let src = (self.addr + offset) as *const u8;
+            let left =
+                with_user_access(|| unsafe { __copy_user(dst[offset..].as_mut_ptr(), src, len) });
+            if left != 0 {
+                return Err(OsError::InvalidParam);
+            }
+            Ok(true)
+        })
+    }
+
+    /// Copy `src` to the start of the range
+    pub fn copy_to_user(&self, memory: &Mutex<UserSpace>, src: &[u8]) -> Result<(), OsError> {
+        if src.len() > self.len {
+            return Err(OsError::InvalidParam);
+        }
+        self.for_each_page(
+            memory,
+            src.len(),
+            UserPageFaultType::Write,
+            |offset, len| {
+                let dst = (self.addr + offset) as *mut u8;
+                let left =
+                    with_user_access(|| unsafe { __copy_user(dst, src[offset..].as_ptr(), len) });
+                if left != 0 {
+                    return Err(OsError::InvalidParam);
+                }
+                Ok(true)
+            },
+        )
+    }
+
+    /// Copy the NUL-terminated string at the start of the range into `dst`.
+    /// Returns its length, or the length of the range or `dst` if no NUL is
+    /// found within them.
+    pub fn strncpy_from_user(
+        &self,
+        memory: &Mutex<UserSpace>,
+        dst: &mut [u8],
+    ) -> Result<usize, OsError> {
+        let max = usize::min(self.len, dst.len());
+        let mut copied = 0;
+        self.for_each_page(memory, max, UserPageFaultType::Read, |offset, len| {
+            let src = (self.addr + offset) as *const u8;
+            let n = with_user_access(|| unsafe {
+                __strncpy_user(dst[offset..].as_mut_ptr(), src, len)
+            });
+            if n < 0 {
+                return Err(OsError::InvalidParam);
+            }
+            copied += n as usize;
+            Ok(n as usize == len)
+        })?;
+        Ok(copied)
+    }
+
+    /// Like `strncpy_from_user`, into a buffer which grows with the string
+    pub fn strndup_from_user(&self, memory: &Mutex<UserSpace>) -> Result<Vec<u8>, OsError> {
+        let mut buf = Vec::new();
+        self.for_each_page(memory, self.len, UserPageFaultType::Read, |offset, len| {
+            let src = (self.addr + offset) as *const u8;
+            buf.resize(offset + len, 0);
+            let n = with_user_access(|| unsafe {
+                __strncpy_user(buf[offset..].as_mut_ptr(), src, len)
+            });
+            if n < 0 {
+                return Err(OsError::InvalidParam);
+            }
+            buf.truncate(offset + n as usize);
+            Ok(n as usize == len)
+        })?;
+        Ok(buf)
+    }
+}
+
+/// A pointer to a `T` in user memory
+#[derive(Debug, Clone, Copy)]
+pub struct UserPtr<T> {
+    addr: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> UserPtr<T> {
+    pub fn new(addr: usize) -> Self {
+        UserPtr {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    fn slice(&self) -> Result<UserSlice, OsError> {
+        UserSlice::new(self.addr, size_of::<T>())
+    }
+
+    pub fn write(&self, memory: &Mutex<UserSpace>, value: &T) -> Result<(), OsError> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        self.slice()?.copy_to_user(memory, bytes)
+    }
+}
+
+impl<T: Copy> UserPtr<T> {
+    /// Read the value, `T` must be valid for any bit pattern
+    pub fn read(&self, memory: &Mutex<UserSpace>) -> Result<T, OsError> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        self.slice()?.copy_from_user(memory, bytes)?;
+        Ok(unsafe { value.assume_init() })
+    }
+}
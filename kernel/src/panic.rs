@@ -5,7 +5,10 @@ use arch::get_hart_id;
 use log::error;
 
 use crate::{
-    mm::layout::{__bss_end, __data_end, __text_end, __text_start},
+    mm::{
+        kstack,
+        layout::{__bss_end, __data_end, __text_end, __text_start},
+    },
     task,
 };
 
@@ -71,17 +74,21 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 fn backtrace() -> String {
+    backtrace_from(arch::ra(), arch::fp())
+}
+
+/// Walk the frame records from `fp`, the first one returning to `ra`
+pub fn backtrace_from(ra: usize, fp: usize) -> String {
     let mut result = String::new();
     #[cfg(feature = "print_symbol")]
     let symbols = read_symbol();
     let mut depth = 0;
-    let mut current_ra = arch::ra();
-    let mut current_fp = arch::fp();
+    let mut current_ra = ra;
+    let mut current_fp = fp;
     result.push_str("\nBacktrace:\n");
     while current_ra >= __text_start as usize
         && current_ra <= __text_end as usize
-        && current_fp >= __data_end as usize
-        && current_fp <= __bss_end as usize
+        && is_frame_pointer(current_fp)
     {
         #[cfg(feature = "print_symbol")]
         {
@@ -121,6 +128,14 @@ fn backtrace() -> String {
     result
 }
 
+/// Whether the frame record below `fp` can be read, the stack may be one of
+/// the boot stacks or a guarded kernel stack
+fn is_frame_pointer(fp: usize) -> bool {
+    let record = fp.wrapping_sub(2 * size_of::<usize>());
+    (fp >= __data_end as usize && fp <= __bss_end as usize)
+        || (kstack::contains(record) && kstack::contains(fp - 1))
+}
+
 #[cfg(feature = "print_symbol")]
 fn read_symbol() -> alloc::vec::Vec<((usize, usize), &'static str)> {
     use xmas_elf::{
@@ -0,0 +1,54 @@
+//! Entropy for address space randomization. The state is seeded from the
+//! device tree and every draw mixes in the timer, which is good enough to
+//! make layouts unpredictable but not for cryptography.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use fdt::Fdt;
+use log::{info, warn};
+use riscv::register::time;
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+static STATE: AtomicU64 = AtomicU64::new(GOLDEN_GAMMA);
+
+/// Finalizer of splitmix64
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+pub fn init(device_tree: &Fdt) {
+    let mut seed = time::read64();
+    let mut seeded = false;
+    if let Some(chosen) = device_tree.find_node("/chosen") {
+        for prop in chosen
+            .properties()
+            .filter(|prop| prop.name == "rng-seed" || prop.name == "kaslr-seed")
+        {
+            for chunk in prop.value.chunks(size_of::<u64>()) {
+                let mut bytes = [0; size_of::<u64>()];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                seed = mix(seed ^ u64::from_le_bytes(bytes));
+            }
+            seeded = true;
+        }
+    }
+    if seeded {
+        info!("Seeded the entropy pool from the device tree.");
+    } else {
+        warn!("No seed in the device tree, randomization relies on the timer.");
+    }
+    STATE.store(mix(seed), Ordering::Relaxed);
+}
+
+pub fn random() -> u64 {
+    let state = STATE.fetch_add(GOLDEN_GAMMA, Ordering::Relaxed);
+    mix(state ^ time::read64())
+}
+
+/// A random number in `0..bound`, `bound` must not be 0
+pub fn below(bound: usize) -> usize {
+    (random() % bound as u64) as usize
+}
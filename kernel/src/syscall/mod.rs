@@ -7,15 +7,22 @@ use alloc::{ffi::c_str, sync::Arc};
 use log::trace;
 
 use crate::{
-    console::getchar,
+    console::getchar_or_wait,
     error::OsError,
-    mm::{addr::VirtAddr, address_space::is_illegal_user_va_range, consts::PAGE_SIZE},
+    mm::{
+        addr::VirtAddr,
+        address_space::is_illegal_user_va_range,
+        consts::PAGE_SIZE,
+        file,
+        frame::{self, ORDER},
+        uaccess::{UserPtr, UserSlice},
+    },
     print,
     task::{
         pid::Pid,
         schedule,
         taskdef::{IpcStatus, TaskControlBlock, TaskStatus},
-        user_space::UserAreaPerm,
+        user_space::{UserAreaPerm, with_swap_io},
     },
 };
 
@@ -48,6 +55,12 @@ enum Syscall {
     Fsync = 24,
     Ftruncate = 25,
     Remove = 26,
+    Brk = 27,
+    Sysinfo = 28,
+    Mmap = 29,
+    Msync = 30,
+    Exit = 31,
+    StackLimit = 32,
     Unhandled = 255,
 }
 
@@ -81,6 +94,12 @@ impl From<usize> for Syscall {
             24 => Syscall::Fsync,
             25 => Syscall::Ftruncate,
             26 => Syscall::Remove,
+            27 => Syscall::Brk,
+            28 => Syscall::Sysinfo,
+            29 => Syscall::Mmap,
+            30 => Syscall::Msync,
+            31 => Syscall::Exit,
+            32 => Syscall::StackLimit,
             _ => Syscall::Unhandled,
         }
     }
@@ -108,9 +127,17 @@ pub fn do_syscall() {
         Syscall::Panic => sys_panic(task, args[0]),
         Syscall::IpcTrySend => sys_ipc_try_send(task, args[0], args[1], args[2], args[3]),
         Syscall::IpcRecv => sys_ipc_recv(task, args[0]),
-        Syscall::Getchar => sys_getchar(),
+        Syscall::Getchar => sys_getchar(task),
+        Syscall::Open => sys_open(task, args[0], args[1]),
+        Syscall::Close => sys_close(task, args[0]),
         Syscall::WriteDev => sys_write_dev(task, args[0], args[1], args[2]),
         Syscall::ReadDev => sys_read_dev(task, args[0], args[1], args[2]),
+        Syscall::Brk => sys_brk(task, args[0]),
+        Syscall::Sysinfo => sys_sysinfo(task, args[0]),
+        Syscall::Mmap => sys_mmap(task, args[0], args[1], args[2], args[3], args[4], args[5]),
+        Syscall::Msync => sys_msync(task, args[0], args[1]),
+        Syscall::Exit => sys_exit(task, args[0]),
+        Syscall::StackLimit => sys_stack_limit(task, args[0]),
         _ => OsError::BadSyscall.into(),
     };
 }
@@ -129,40 +156,38 @@ fn sys_putchar(c: usize) -> usize {
 
 fn sys_print_console(task: Arc<TaskControlBlock>, ptr: usize, len: usize) -> usize {
     syscall_trace!(Syscall::PrintConsole, "ptr: 0x{:x}, len: {}", ptr, len);
-    if is_illegal_user_va_range(ptr, len) {
-        return OsError::InvalidParam.into();
+    // Print exactly `len` bytes, NUL bytes included, a page at a time. What
+    // comes before an invalid UTF-8 sequence is printed before it is reported.
+    if let Err(e) = UserSlice::new(ptr, len) {
+        return e.into();
     }
-    unsafe {
-        riscv::register::sstatus::set_sum();
-    }
-    let p = ptr as *const u8;
-    let mut q = p;
-    let maxlen = len;
-    let mut rlen = 0;
-    while unsafe { *q } != 0 {
-        rlen += 1;
-        if rlen > maxlen {
-            break;
-        }
-        q = unsafe { q.add(1) };
-    }
-    let str = unsafe { core::slice::from_raw_parts(p, rlen) };
-    match core::str::from_utf8(str) {
-        Ok(s) => {
-            print!("{}", &s[..len.min(s.len())]);
-            unsafe {
-                riscv::register::sstatus::clear_sum();
-            }
-            OsError::Success
-        }
-        Err(_) => {
-            unsafe {
-                riscv::register::sstatus::clear_sum();
-            }
-            OsError::InvalidParam
+    let mut buf = [0u8; PAGE_SIZE];
+    // Length of the incomplete character the last chunk ended with
+    let mut carry = 0;
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(PAGE_SIZE - carry);
+        let dst = &mut buf[carry..carry + n];
+        if let Err(e) =
+            UserSlice::new(ptr + done, n).and_then(|s| s.copy_from_user(task.memory(), dst))
+        {
+            return e.into();
         }
+        done += n;
+        let filled = carry + n;
+        let valid = match core::str::from_utf8(&buf[..filled]) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return OsError::InvalidParam.into(),
+        };
+        print!("{}", core::str::from_utf8(&buf[..valid]).unwrap());
+        buf.copy_within(valid..filled, 0);
+        carry = filled - valid;
     }
-    .into()
+    if carry != 0 {
+        return OsError::InvalidParam.into();
+    }
+    OsError::Success.into()
 }
 
 fn sys_get_task_id(task: Arc<TaskControlBlock>) -> usize {
@@ -191,6 +216,13 @@ fn sys_task_destroy(task: Arc<TaskControlBlock>, pid: usize) -> usize {
     .into()
 }
 
+/// End the calling task with `code`
+fn sys_exit(task: Arc<TaskControlBlock>, code: usize) -> usize {
+    syscall_trace!(Syscall::Exit, "{}", code);
+    task.exit_with(code);
+    OsError::Success.into()
+}
+
 fn sys_set_tlb_mod_entry(task: Arc<TaskControlBlock>, pid: usize, entry: usize) -> usize {
     syscall_trace!(
         Syscall::SetTlbModEntry,
@@ -262,11 +294,8 @@ pub fn sys_mem_map(
         (Some(src), Some(dst)) => (src, dst),
         _ => return OsError::BadTask.into(),
     };
-    match src_task
-        .memory()
-        .lock()
-        .find_frame(VirtAddr(src_va).floor_page())
-    {
+    let vpn = VirtAddr(src_va).floor_page();
+    match with_swap_io(src_task.memory(), |memory| memory.find_frame(vpn)) {
         Ok(frame) => {
             let perm = match UserAreaPerm::from_bits(perm) {
                 Some(perm) => perm,
@@ -279,7 +308,7 @@ pub fn sys_mem_map(
                 .map(|_| OsError::Success)
                 .unwrap_or_else(|e| e)
         }
-        Err(e) => e,
+        Err(e) => e.into(),
     }
     .into()
 }
@@ -329,17 +358,16 @@ pub fn sys_set_env_status(task: Arc<TaskControlBlock>, pid: usize, status: usize
 
 pub fn sys_set_trapframe(task: Arc<TaskControlBlock>, pid: usize, ptr: usize) -> usize {
     syscall_trace!(Syscall::SetTrapframe, "pid: {}, ptr: 0x{:x}", pid, ptr);
-    if is_illegal_user_va_range(ptr, 34 * size_of::<usize>()) {
-        return OsError::InvalidParam.into();
-    }
+    let context = match UserPtr::<[usize; 34]>::new(ptr).read(task.memory()) {
+        Ok(context) => context,
+        Err(e) => return e.into(),
+    };
     if let Some(task) = task.get_task(Pid(pid)) {
         if task.status() == TaskStatus::Running {
             task.set_yield_flag(true);
             while task.status() == TaskStatus::Running {}
         }
-        unsafe {
-            task.set_user_context(ptr);
-        }
+        task.set_user_context(&context);
         OsError::Success
     } else {
         OsError::BadTask
@@ -349,27 +377,16 @@ pub fn sys_set_trapframe(task: Arc<TaskControlBlock>, pid: usize, ptr: usize) ->
 
 pub fn sys_panic(task: Arc<TaskControlBlock>, ptr: usize) -> usize {
     syscall_trace!(Syscall::Panic, "ptr: 0x{:x}", ptr);
-    if is_illegal_user_va_range(ptr, PAGE_SIZE) {
-        return OsError::InvalidParam.into();
-    }
-    unsafe {
-        riscv::register::sstatus::set_sum();
-    }
-    let p = ptr as *const u8;
-    let mut q = p;
-    let maxlen = 512;
-    let mut len = 0;
-    while unsafe { *q } != 0 {
-        len += 1;
-        if len > maxlen {
-            break;
-        }
-        q = unsafe { q.add(1) };
-    }
-    let panic_info = unsafe { core::slice::from_raw_parts(p, len) };
+    let mut panic_info = [0; 512];
+    let len = match UserSlice::new(ptr, panic_info.len())
+        .and_then(|s| s.strncpy_from_user(task.memory(), &mut panic_info))
+    {
+        Ok(len) => len,
+        Err(e) => return e.into(),
+    };
     panic!(
         "{}",
-        core::str::from_utf8(panic_info).unwrap_or("Invalid user panic info")
+        core::str::from_utf8(&panic_info[..len]).unwrap_or("Invalid user panic info")
     );
 }
 
@@ -408,11 +425,8 @@ pub fn sys_ipc_try_send(
                 if is_illegal_user_va_range(src_va, PAGE_SIZE) {
                     return OsError::InvalidParam.into();
                 }
-                match task
-                    .memory()
-                    .lock()
-                    .find_frame(VirtAddr(src_va).floor_page())
-                {
+                let vpn = VirtAddr(src_va).floor_page();
+                match with_swap_io(task.memory(), |memory| memory.find_frame(vpn)) {
                     Ok(frame) => {
                         match dst
                             .memory()
@@ -423,7 +437,7 @@ pub fn sys_ipc_try_send(
                             Err(e) => e,
                         }
                     }
-                    Err(e) => e,
+                    Err(e) => e.into(),
                 }
             } else {
                 OsError::Success
@@ -448,17 +462,18 @@ pub fn sys_ipc_recv(task: Arc<TaskControlBlock>, dst_va: usize) -> usize {
     OsError::Success.into()
 }
 
-pub fn sys_getchar() -> usize {
+/// Read a byte of console input, blocking until there is one
+pub fn sys_getchar(task: Arc<TaskControlBlock>) -> usize {
     syscall_trace!(Syscall::Getchar, "");
-    let mut c: u8;
-    // TODO: interrupt instead of busy waiting
-    loop {
-        c = getchar();
-        if c != 0 {
-            break;
+    match getchar_or_wait(&task) {
+        Some(ch) => ch as usize,
+        None => {
+            // Make the call again once the task runs, keeping a0 intact
+            let context = task.get_context_mut();
+            context.sepc -= 4;
+            context.uregs[10]
         }
     }
-    c as usize
 }
 
 pub fn sys_write_dev(task: Arc<TaskControlBlock>, dev: usize, pa: usize, len: usize) -> usize {
@@ -483,6 +498,166 @@ pub fn sys_read_dev(task: Arc<TaskControlBlock>, dev: usize, pa: usize, len: usi
     0
 }
 
+pub fn sys_brk(task: Arc<TaskControlBlock>, addr: usize) -> usize {
+    syscall_trace!(Syscall::Brk, "addr: 0x{:x}", addr);
+    let mut memory = task.memory().lock();
+    if addr == 0 {
+        return memory.brk().0;
+    }
+    match memory.set_brk(VirtAddr(addr)) {
+        Ok(brk) => brk.0,
+        Err(e) => e.into(),
+    }
+}
+
+/// Set the maximum size of the user stack to `limit` bytes, or only return it
+/// if `limit` is 0. Returns the limit in effect.
+pub fn sys_stack_limit(task: Arc<TaskControlBlock>, limit: usize) -> usize {
+    syscall_trace!(Syscall::StackLimit, "limit: 0x{:x}", limit);
+    let mut memory = task.memory().lock();
+    if limit == 0 {
+        return memory.stack_limit();
+    }
+    match memory.set_stack_limit(limit) {
+        Ok(limit) => limit,
+        Err(e) => e.into(),
+    }
+}
+
+/// Memory statistics reported to user space, all sizes are in pages.
+#[repr(C)]
+pub struct SysInfo {
+    pub page_size: usize,
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub used_pages: usize,
+    pub free_blocks: [usize; ORDER],
+}
+
+pub fn sys_sysinfo(task: Arc<TaskControlBlock>, ptr: usize) -> usize {
+    syscall_trace!(Syscall::Sysinfo, "ptr: 0x{:x}", ptr);
+    let stats = frame::stats();
+    let info = SysInfo {
+        page_size: PAGE_SIZE,
+        total_pages: stats.total,
+        free_pages: stats.free,
+        used_pages: stats.used,
+        free_blocks: stats.free_blocks,
+    };
+    match UserPtr::new(ptr).write(task.memory(), &info) {
+        Ok(()) => OsError::Success,
+        Err(e) => e,
+    }
+    .into()
+}
+
+/// Longest file name `sys_open` takes
+const NAME_MAX: usize = 64;
+
+/// Open the file called by the `len` bytes at `ptr`, returns its descriptor
+pub fn sys_open(task: Arc<TaskControlBlock>, ptr: usize, len: usize) -> usize {
+    syscall_trace!(Syscall::Open, "ptr: 0x{:x}, len: {}", ptr, len);
+    if len > NAME_MAX {
+        return OsError::BadPath.into();
+    }
+    let mut name = [0u8; NAME_MAX];
+    if let Err(e) =
+        UserSlice::new(ptr, len).and_then(|s| s.copy_from_user(task.memory(), &mut name))
+    {
+        return e.into();
+    }
+    let Ok(name) = core::str::from_utf8(&name[..len]) else {
+        return OsError::BadPath.into();
+    };
+    let Some(file) = file::open(name) else {
+        return OsError::NotFound.into();
+    };
+    match task.open_file(file) {
+        Ok(fd) => fd,
+        Err(e) => e.into(),
+    }
+}
+
+pub fn sys_close(task: Arc<TaskControlBlock>, fd: usize) -> usize {
+    syscall_trace!(Syscall::Close, "fd: {}", fd);
+    match task.close_file(fd) {
+        Ok(()) => OsError::Success,
+        Err(e) => e,
+    }
+    .into()
+}
+
+pub const MAP_SHARED: usize = 1;
+pub const MAP_PRIVATE: usize = 2;
+
+/// Map `len` bytes of the open file `fd` starting at `offset` at `va`, or
+/// anywhere if `va` is 0. Returns the address of the mapping.
+pub fn sys_mmap(
+    task: Arc<TaskControlBlock>,
+    va: usize,
+    len: usize,
+    perm: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> usize {
+    syscall_trace!(
+        Syscall::Mmap,
+        "va: 0x{:x}, len: 0x{:x}, perm: 0x{:x}, flags: 0x{:x}, fd: {}, offset: 0x{:x}",
+        va,
+        len,
+        perm,
+        flags,
+        fd,
+        offset
+    );
+    let shared = match flags {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return OsError::InvalidParam.into(),
+    };
+    let Some(perm) = UserAreaPerm::from_bits(perm) else {
+        return OsError::InvalidParam.into();
+    };
+    if len == 0 || !offset.is_multiple_of(PAGE_SIZE) {
+        return OsError::InvalidParam.into();
+    }
+    let Some(file) = task.file(fd) else {
+        return OsError::InvalidParam.into();
+    };
+    let va = (va != 0).then_some(VirtAddr(va));
+    let pages = len.div_ceil(PAGE_SIZE);
+    match task
+        .memory()
+        .lock()
+        .mmap(va, pages, perm, file, offset / PAGE_SIZE, shared)
+    {
+        Ok(va) => va.0,
+        Err(e) => e.into(),
+    }
+}
+
+/// Write the changes to shared file mappings in `va..va + len` back
+pub fn sys_msync(task: Arc<TaskControlBlock>, va: usize, len: usize) -> usize {
+    syscall_trace!(Syscall::Msync, "va: 0x{:x}, len: 0x{:x}", va, len);
+    if !va.is_multiple_of(PAGE_SIZE) || is_illegal_user_va_range(va, len) {
+        return OsError::InvalidParam.into();
+    }
+    let start = VirtAddr(va).floor_page();
+    let end = VirtAddr(va + len).ceil_page();
+    // Written back with the address space unlocked
+    let ranges = task.memory().lock().msync(start, end);
+    let mut result = Ok(());
+    for (file, range) in ranges {
+        result = result.and(file.run_io(range));
+    }
+    match result {
+        Ok(()) => OsError::Success,
+        Err(e) => e,
+    }
+    .into()
+}
+
 pub fn sys_unhandled() -> usize {
     OsError::BadSyscall.into()
 }
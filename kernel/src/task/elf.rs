@@ -0,0 +1,332 @@
+//! Validation of user ELF images. Everything the loader relies on is checked
+//! here, so a malformed image is rejected before anything is mapped.
+
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use xmas_elf::{
+    ElfFile,
+    header::{self, Class, Machine},
+    program::{self, ProgramHeader64},
+};
+
+use crate::{
+    config::USER_ASLR,
+    error::OsError,
+    mm::{
+        address_space::{U_DATA_BEG, U_DATA_END},
+        consts::PAGE_SIZE,
+    },
+    random, round_down, round_up,
+};
+
+use super::user_space::UserAreaPerm;
+
+/// A loadable segment, bytes of `vaddr..vaddr + mem_size` past `file` are
+/// zero-filled
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub vaddr: usize,
+    pub mem_size: usize,
+    /// Range of the image holding the initialized part
+    pub file: Range<usize>,
+    pub perm: UserAreaPerm,
+}
+
+impl Segment {
+    pub fn end(&self) -> usize {
+        self.vaddr + self.mem_size
+    }
+}
+
+// Dynamic section tags and relocation types the loader understands
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const DT_RELSZ: u64 = 18;
+const DT_RELRSZ: u64 = 35;
+const R_RISCV_NONE: u64 = 0;
+const R_RISCV_RELATIVE: u64 = 3;
+const RELA_SIZE: usize = 24;
+
+// Auxiliary vector entries passed on the initial stack
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY: usize = 9;
+pub const AT_RANDOM: usize = 25;
+/// Number of harts, not a tag Linux defines
+pub const AT_HART_COUNT: usize = 0x1000;
+
+/// Where a loaded image ended up, as reported through the auxiliary vector
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedElf {
+    pub entry: usize,
+    /// Address of the program header table, if a segment maps it
+    pub phdr: Option<usize>,
+    pub phnum: usize,
+}
+
+/// A validated executable. Addresses are those it was linked at, a
+/// position-independent one is moved by `load_bias`.
+pub struct Elf<'a> {
+    pub image: &'a [u8],
+    pub entry: usize,
+    /// Sorted by address, no two of them overlap
+    pub segments: Vec<Segment>,
+    /// `ET_DYN`, may be loaded anywhere
+    pub pie: bool,
+    /// Link address of the program header table, if a segment maps it
+    pub phdr: Option<usize>,
+    pub phnum: usize,
+    // Largest segment alignment
+    align: usize,
+    // Part of the image holding the `Elf64_Rela` entries
+    rela: Range<usize>,
+}
+
+fn read_u64(image: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Self, OsError> {
+        let elf = ElfFile::new(image).map_err(|_| OsError::NotExec)?;
+        let pt2 = &elf.header.pt2;
+        let pie = match pt2.type_().as_type() {
+            header::Type::Executable => false,
+            header::Type::SharedObject => true,
+            _ => return Err(OsError::NotExec),
+        };
+        if elf.header.pt1.class() != Class::SixtyFour
+            || !matches!(pt2.machine().as_machine(), Machine::RISC_V)
+        {
+            return Err(OsError::NotExec);
+        }
+        // xmas_elf slices the program header table without checking it
+        let table_size = pt2.ph_count() as usize * size_of::<ProgramHeader64>();
+        if pt2.ph_entry_size() as usize != size_of::<ProgramHeader64>()
+            || !(pt2.ph_offset() as usize).is_multiple_of(align_of::<ProgramHeader64>())
+            || (pt2.ph_offset() as usize)
+                .checked_add(table_size)
+                .is_none_or(|end| end > image.len())
+        {
+            return Err(OsError::NotExec);
+        }
+
+        let mut segments = Vec::new();
+        let mut dynamic = None;
+        let mut max_align = PAGE_SIZE;
+        for ph in elf.program_iter() {
+            let ty = ph.get_type().map_err(|_| OsError::NotExec)?;
+            if ty == program::Type::Dynamic {
+                let offset = ph.offset() as usize;
+                let size = ph.file_size() as usize;
+                if offset % 8 != 0 || offset.checked_add(size).is_none_or(|end| end > image.len()) {
+                    return Err(OsError::NotExec);
+                }
+                dynamic = Some(offset..offset + size);
+            }
+            if ty != program::Type::Load {
+                continue;
+            }
+            let vaddr = ph.virtual_addr() as usize;
+            let mem_size = ph.mem_size() as usize;
+            let offset = ph.offset() as usize;
+            let file_size = ph.file_size() as usize;
+            let align = ph.align() as usize;
+            let perm = UserAreaPerm::from(ph.flags());
+            if mem_size == 0 {
+                continue;
+            }
+            let file_end = offset.checked_add(file_size).ok_or(OsError::NotExec)?;
+            let end = vaddr.checked_add(mem_size).ok_or(OsError::NotExec)?;
+            if file_size > mem_size
+                || file_end > image.len()
+                || (!pie && (vaddr < U_DATA_BEG || end > U_DATA_END))
+                || (pie && end > U_DATA_END - U_DATA_BEG)
+                || perm.contains(UserAreaPerm::W | UserAreaPerm::X)
+                || (align > 1 && (!align.is_power_of_two() || vaddr % align != offset % align))
+            {
+                return Err(OsError::NotExec);
+            }
+            max_align = max_align.max(align);
+            segments.push(Segment {
+                vaddr,
+                mem_size,
+                file: offset..file_end,
+                perm,
+            });
+        }
+        segments.sort_unstable_by_key(|segment| segment.vaddr);
+        for pair in segments.windows(2) {
+            let [prev, next] = pair else { unreachable!() };
+            if prev.end() > next.vaddr {
+                return Err(OsError::NotExec);
+            }
+            // A page shared by both is mapped once, with one permission
+            if (prev.end() - 1) / PAGE_SIZE == next.vaddr / PAGE_SIZE && prev.perm != next.perm {
+                return Err(OsError::NotExec);
+            }
+        }
+
+        let Some(first) = segments.first() else {
+            return Err(OsError::NotExec);
+        };
+        let span = round_up!(segments.last().unwrap().end(), PAGE_SIZE)
+            - round_down!(first.vaddr, max_align);
+        if pie && round_up!(U_DATA_BEG, max_align) + span > U_DATA_END {
+            return Err(OsError::NotExec);
+        }
+
+        let entry = pt2.entry_point() as usize;
+        if !segments.iter().any(|segment| {
+            segment.perm.contains(UserAreaPerm::X)
+                && (segment.vaddr..segment.end()).contains(&entry)
+        }) {
+            return Err(OsError::NotExec);
+        }
+        let ph_offset = pt2.ph_offset() as usize;
+        let phdr = segments
+            .iter()
+            .find(|segment| {
+                segment.file.start <= ph_offset && ph_offset + table_size <= segment.file.end
+            })
+            .map(|segment| segment.vaddr + (ph_offset - segment.file.start));
+        let mut elf = Elf {
+            image,
+            entry,
+            segments,
+            pie,
+            phdr,
+            phnum: pt2.ph_count() as usize,
+            align: max_align,
+            rela: 0..0,
+        };
+        if pie {
+            if let Some(dynamic) = dynamic {
+                elf.rela = elf.relocation_table(dynamic)?;
+            }
+            // Any relocation but a relative one needs a dynamic linker
+            for offset in elf.rela.clone().step_by(RELA_SIZE) {
+                let target = read_u64(image, offset) as usize;
+                match read_u64(image, offset + 8) & 0xffff_ffff {
+                    R_RISCV_NONE => {}
+                    R_RISCV_RELATIVE if target % 8 == 0 && elf.writes_loaded(target) => {}
+                    _ => return Err(OsError::NotExec),
+                }
+            }
+        }
+        Ok(elf)
+    }
+
+    /// Locate the `Elf64_Rela` table through the dynamic section
+    fn relocation_table(&self, dynamic: Range<usize>) -> Result<Range<usize>, OsError> {
+        let (mut addr, mut size) = (None, 0);
+        for entry in dynamic
+            .step_by(16)
+            .take_while(|entry| entry + 16 <= self.image.len())
+        {
+            let value = read_u64(self.image, entry + 8) as usize;
+            match read_u64(self.image, entry) {
+                DT_NULL => break,
+                DT_RELA => addr = Some(value),
+                DT_RELASZ => size = value,
+                DT_RELAENT if value != RELA_SIZE => return Err(OsError::NotExec),
+                DT_REL | DT_RELSZ | DT_RELRSZ if value != 0 => return Err(OsError::NotExec),
+                _ => {}
+            }
+        }
+        let Some(addr) = addr else {
+            return Ok(0..0);
+        };
+        // The table is read from the image, it must be initialized data
+        let offset = self
+            .segments
+            .iter()
+            .find(|segment| {
+                addr >= segment.vaddr
+                    && addr
+                        .checked_add(size)
+                        .is_some_and(|end| end <= segment.vaddr + segment.file.len())
+            })
+            .map(|segment| segment.file.start + (addr - segment.vaddr))
+            .ok_or(OsError::NotExec)?;
+        if size % RELA_SIZE != 0 || offset % 8 != 0 {
+            return Err(OsError::NotExec);
+        }
+        Ok(offset..offset + size)
+    }
+
+    /// Whether the 8 bytes at `addr` are part of a segment
+    fn writes_loaded(&self, addr: usize) -> bool {
+        self.segments.iter().any(|segment| {
+            addr >= segment.vaddr && addr.checked_add(8).is_some_and(|end| end <= segment.end())
+        })
+    }
+
+    /// Link page numbers the segments span
+    pub fn pages(&self) -> Range<usize> {
+        let end = round_up!(self.segments.last().unwrap().end(), PAGE_SIZE);
+        self.segments[0].vaddr / PAGE_SIZE..end / PAGE_SIZE
+    }
+
+    /// Whether any segment has initialized bytes in the page at link address
+    /// `va`
+    pub fn is_initialized(&self, va: usize) -> bool {
+        self.segments.iter().any(|segment| {
+            segment.vaddr < va + PAGE_SIZE && va < segment.vaddr + segment.file.len()
+        })
+    }
+
+    /// Copy the initialized bytes the segments place in the page at link
+    /// address `va` into `page`, which is zeroed
+    pub fn fill_page(&self, va: usize, page: &mut [u8]) {
+        for segment in &self.segments {
+            let start = segment.vaddr.max(va);
+            let end = (segment.vaddr + segment.file.len()).min(va + PAGE_SIZE);
+            if start < end {
+                let offset = segment.file.start + (start - segment.vaddr);
+                page[start - va..end - va].copy_from_slice(&self.image[offset..][..end - start]);
+            }
+        }
+    }
+
+    /// Offsets of the relative relocations and the values to store there
+    /// before adding the load bias
+    pub fn relocations(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.rela
+            .clone()
+            .step_by(RELA_SIZE)
+            .filter(|&offset| read_u64(self.image, offset + 8) & 0xffff_ffff == R_RISCV_RELATIVE)
+            .map(|offset| {
+                (
+                    read_u64(self.image, offset) as usize,
+                    read_u64(self.image, offset + 16) as usize,
+                )
+            })
+    }
+
+    /// Distance from the link addresses to the load addresses. An executable
+    /// stays where it was linked, a position-independent one goes to a random
+    /// place in the data region.
+    pub fn load_bias(&self) -> usize {
+        if !self.pie {
+            return 0;
+        }
+        let start = round_down!(self.segments[0].vaddr, self.align);
+        let end = round_up!(self.segments.last().unwrap().end(), PAGE_SIZE);
+        let first = round_up!(U_DATA_BEG, self.align);
+        let last = round_down!(U_DATA_END - (end - start), self.align);
+        let slot = if USER_ASLR {
+            random::below((last - first) / self.align + 1)
+        } else {
+            0
+        };
+        (first + slot * self.align).wrapping_sub(start)
+    }
+}
@@ -0,0 +1,135 @@
+//! Pages of loaded executables, shared by every task running the same image.
+//! Images are told apart by their address and length rather than a hash of
+//! their contents. They are `&'static [u8]` in kernel memory, so an address
+//! is never reused for another image and entries need no invalidation; an
+//! image source which can free its memory would have to drop its key with
+//! `forget`. Only weak references are kept, so a page goes away with the
+//! last task mapping it.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use crate::{
+    Mutex,
+    error::OsError,
+    mm::{
+        addr::PhysAddr,
+        consts::PAGE_SIZE,
+        frame::{self, FrameTracker},
+        page::PageFlags,
+    },
+};
+
+use super::elf::Elf;
+
+/// Address and length of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ImageKey(usize, usize);
+
+impl ImageKey {
+    pub fn of(image: &'static [u8]) -> Self {
+        ImageKey(image.as_ptr() as usize, image.len())
+    }
+}
+
+static CACHE: Mutex<BTreeMap<ImageKey, BTreeMap<usize, Weak<FrameTracker>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// The frame holding page `index` of the loaded image `key`, filled by `fill`
+/// with the page zeroed if no task has it mapped
+fn page(
+    key: ImageKey,
+    index: usize,
+    fill: impl FnOnce(&mut [u8]),
+) -> Result<Arc<FrameTracker>, OsError> {
+    let mut cache = CACHE.lock();
+    let pages = cache.entry(key).or_default();
+    if let Some(frame) = pages.get(&index).and_then(Weak::upgrade) {
+        return Ok(frame);
+    }
+    let frame = frame::alloc()?;
+    fill(unsafe { PhysAddr::from(frame.ppn).as_mut_page_slice() });
+    frame.page().clear_flags(PageFlags::ZEROED);
+    let frame = Arc::new(frame);
+    pages.insert(index, Arc::downgrade(&frame));
+    Ok(frame)
+}
+
+/// Forget the pages no task maps anymore
+pub fn prune() {
+    let mut cache = CACHE.lock();
+    cache.retain(|_, pages| {
+        pages.retain(|_, frame| frame.strong_count() > 0);
+        !pages.is_empty()
+    });
+}
+
+/// Drop the pages of `key`, for when the memory of its image is reused
+#[allow(dead_code)]
+pub fn forget(key: ImageKey) {
+    CACHE.lock().remove(&key);
+}
+
+/// An executable whose pages are loaded on their first access
+pub struct Image {
+    elf: Elf<'static>,
+    key: ImageKey,
+    // Relative relocations as offset and addend, by the page they write to
+    relocations: BTreeMap<usize, Vec<(usize, usize)>>,
+}
+
+impl Image {
+    pub fn new(elf: Elf<'static>) -> Self {
+        let key = ImageKey::of(elf.image);
+        let mut relocations = BTreeMap::<usize, Vec<_>>::new();
+        // Relocation targets are aligned, so none spans two pages
+        for (offset, addend) in elf.relocations() {
+            relocations
+                .entry(offset / PAGE_SIZE)
+                .or_default()
+                .push((offset, addend));
+        }
+        Image {
+            elf,
+            key,
+            relocations,
+        }
+    }
+
+    pub fn elf(&self) -> &Elf<'static> {
+        &self.elf
+    }
+
+    /// Page `index` as linked, shared by every task running the image
+    pub fn shared_page(&self, index: usize) -> Result<Arc<FrameTracker>, OsError> {
+        page(self.key, index, |page| {
+            self.elf.fill_page(index * PAGE_SIZE, page)
+        })
+    }
+
+    /// Whether `frame` is the shared frame of page `index`
+    pub fn caches(&self, index: usize, frame: &Arc<FrameTracker>) -> bool {
+        CACHE
+            .lock()
+            .get(&self.key)
+            .and_then(|pages| pages.get(&index))
+            .is_some_and(|page| Weak::as_ptr(page) == Arc::as_ptr(frame))
+    }
+
+    /// Whether page `index` differs between load addresses
+    pub fn is_relocated(&self, index: usize) -> bool {
+        self.relocations.contains_key(&index)
+    }
+
+    /// Apply the relocations of page `index` to `page`, a copy of it loaded
+    /// `bias` bytes from its link address
+    pub fn relocate(&self, index: usize, bias: usize, page: &mut [u8]) {
+        for &(offset, addend) in self.relocations.get(&index).into_iter().flatten() {
+            let value = addend.wrapping_add(bias);
+            page[offset % PAGE_SIZE..][..8].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
@@ -4,11 +4,14 @@ use taskdef::TaskControlBlock;
 
 use crate::{get_hart_count, include_bytes_align_as, mask};
 
+pub mod elf;
 pub mod hart;
+pub mod image_cache;
 pub mod pid;
 pub mod schedule;
 pub mod taskdef;
 pub mod user_space;
+pub mod wait_queue;
 
 const DUMMY: &[u8] = include_bytes_align_as!(
     usize,
@@ -17,13 +20,19 @@ const DUMMY: &[u8] = include_bytes_align_as!(
 // const PAGEFAULT: &[u8] = include_bytes_align_as!(usize, "../../../user/pagefault.b");
 
 pub fn run() -> ! {
+    user_space::image_test(DUMMY);
+    user_space::pie_test();
     let task = TaskControlBlock::new();
-    task.clone().init(DUMMY);
+    task.clone()
+        .init(DUMMY, &["dummy"], &[])
+        .expect("failed to load the user program");
     task.set_priority(2);
     let _ = schedule::SCHEDULER.submit_task(task);
     for _ in 0..25 {
         let task = TaskControlBlock::new();
-        task.clone().init(DUMMY);
+        task.clone()
+            .init(DUMMY, &["dummy"], &[])
+            .expect("failed to load the user program");
         schedule::SCHEDULER
             .submit_task(task)
             .expect("submit task failed");
@@ -10,14 +10,12 @@ use riscv::interrupt::{
 use sync::Lazy;
 
 use crate::{
-    Mutex, config,
+    Mutex, config, drivers,
     error::OsError,
-    get_hart_count,
-    mm::paging::switch_page_table,
-    syscall,
-    task::user_space::UserPageFaultType,
+    get_hart_count, mm, syscall,
+    task::user_space::{UserPageFaultError, UserPageFaultType, with_swap_io},
     timer,
-    trap::{context::UserContext, set_kernel_trap, set_user_trap},
+    trap::{self, context::UserContext, set_kernel_trap, set_user_trap},
     utils::ring_buffer::RingBuffer,
 };
 
@@ -59,11 +57,10 @@ impl Scheduler {
         if let Some(task) = task {
             match task.status() {
                 TaskStatus::Ready => Some(task),
-                TaskStatus::Sleeping => {
-                    // The object which makes the task sleep take the responsibility to wake it up (and return it to the scheduler)
-                    self.alive_task_count.fetch_sub(1, Ordering::Release);
-                    None
-                }
+                // Whoever put the task to sleep wakes it up with `wake`
+                TaskStatus::Sleeping if self.park(&task) => None,
+                // Woken up meanwhile
+                TaskStatus::Sleeping => Some(task),
                 TaskStatus::Running => {
                     panic!(
                         "Task {:?} is running and in queue, should not happen",
@@ -84,6 +81,31 @@ impl Scheduler {
         }
     }
 
+    /// Stop scheduling `task` if it is still asleep. Returns false if it
+    /// was woken up already and should keep running.
+    fn park(&self, task: &TaskControlBlock) -> bool {
+        task.with_status(|status, parked| {
+            let asleep = *status == TaskStatus::Sleeping;
+            parked.store(asleep, Ordering::Relaxed);
+            asleep
+        })
+    }
+
+    /// Make the sleeping `task` runnable again. A task which has not been
+    /// parked yet is still held by a hart, which sees it ready and queues it.
+    pub fn wake(&self, task: Arc<TaskControlBlock>) {
+        let requeue = task.with_status(|status, parked| {
+            if *status != TaskStatus::Sleeping {
+                return false;
+            }
+            *status = TaskStatus::Ready;
+            parked.swap(false, Ordering::Relaxed)
+        });
+        if requeue {
+            self.return_task(task);
+        }
+    }
+
     fn return_task(&self, task: Arc<TaskControlBlock>) {
         match self.queue.push(task) {
             Ok(head) => {
@@ -104,6 +126,9 @@ impl Scheduler {
                 if self.alive_task_count.load(Ordering::Acquire) == 0 {
                     panic!("No task to run");
                 }
+                if config::KERNEL_HEAP_SHRINK {
+                    mm::heap::idle_shrink();
+                }
                 riscv::asm::wfi();
                 continue;
             }
@@ -123,13 +148,15 @@ impl Scheduler {
                         if task.get_yield_flag() {
                             break;
                         }
-                        if task.status() == TaskStatus::Sleeping {
+                        if task.status() == TaskStatus::Sleeping && self.park(&task) {
                             break 'taskloop;
                         }
                     }
                     if task.get_yield_flag() || !self.queue.is_empty() {
                         task.set_yield_flag(false);
-                        self.return_task(task);
+                        if !self.park(&task) {
+                            self.return_task(task);
+                        }
                         break;
                     }
                 },
@@ -141,7 +168,7 @@ impl Scheduler {
     fn execute(&self, task: Arc<TaskControlBlock>) {
         let current_task = get_current_task();
         if !(current_task.is_some() && current_task.unwrap().pid() == task.pid()) {
-            switch_page_table(task.page_table().ppn());
+            task.memory().lock().activate();
             set_current_task(Some(task.clone()));
         }
         task.set_status(TaskStatus::Running);
@@ -168,10 +195,14 @@ impl Scheduler {
                 Interrupt::SupervisorSoft => {
                     unsafe { riscv::register::sip::clear_ssoft() };
                 }
-                Interrupt::SupervisorExternal => todo!(),
+                Interrupt::SupervisorExternal => drivers::plic::handle(),
             },
             Trap::Exception(e) => match e {
-                Exception::UserEnvCall => syscall::do_syscall(),
+                // A kernel exception while acting for the task kills only
+                // the task
+                Exception::UserEnvCall => {
+                    trap::oops::catch(syscall::do_syscall);
+                }
                 Exception::LoadPageFault
                 | Exception::StorePageFault
                 | Exception::InstructionPageFault => {
@@ -187,11 +218,30 @@ impl Scheduler {
                         task.pid(),
                         stval,
                     );
-                    match task.memory().lock().handle_page_fault(stval, ty) {
+                    // Stays `Ok` if the task is killed by an oops
+                    let mut result = Ok(());
+                    trap::oops::catch(|| {
+                        result = with_swap_io(task.memory(), |memory| {
+                            memory.handle_page_fault(stval, ty)
+                        })
+                    });
+                    match result {
                         Ok(()) => {}
-                        Err(_) => {
+                        Err(UserPageFaultError::StackOverflow) => {
+                            warn!(
+                                "User stack overflow, killed. Pid: {:?}, sepc: {:#x}, sp: {:#x}, stval: {:#x}, stack limit: {:#x}",
+                                task.pid(),
+                                task.get_context().sepc,
+                                task.get_context().uregs[2],
+                                stval,
+                                task.memory().lock().stack_limit(),
+                            );
+                            task.exit();
+                        }
+                        Err(e) => {
                             warn!(
-                                "User page fault, killed. Pid: {:?}, sepc: {:#x}, stval: {:#x}\n Full context: {:?}",
+                                "User page fault ({:?}), killed. Pid: {:?}, sepc: {:#x}, stval: {:#x}\n Full context: {:?}",
+                                e,
                                 task.pid(),
                                 task.get_context().sepc,
                                 stval,
@@ -220,7 +270,7 @@ impl Scheduler {
                     );
                 }
             },
-        };
+        }
         unsafe {
             riscv::register::sie::set_ssoft();
         }
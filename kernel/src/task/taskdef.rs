@@ -3,17 +3,22 @@ use core::{
     sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
-use alloc::{boxed::Box, rc::Weak, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, rc::Weak, sync::Arc, vec::Vec};
 use log::trace;
+use xmas_elf::program::ProgramHeader64;
 
 use crate::{
     Mutex,
-    mm::{addr::VirtAddr, address_space::U_STACK_END, paging::page_table::PageTable},
+    config::MAX_OPEN_FILES,
+    error::OsError,
+    get_hart_count,
+    mm::{addr::VirtAddr, consts::PAGE_SIZE, file::File},
     task::hart::{get_current_task, set_current_task},
     trap::context::UserContext,
 };
 
 use super::{
+    elf::{AT_ENTRY, AT_HART_COUNT, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM},
     pid::{Pid, PidHandle, alloc_pid},
     user_space::UserSpace,
 };
@@ -29,7 +34,11 @@ pub struct TaskControlBlock {
     ipc_info: Mutex<IpcInfo>,
     children: Mutex<Vec<Arc<TaskControlBlock>>>,
     memory: Mutex<UserSpace>,
+    // Open files by descriptor
+    files: Mutex<BTreeMap<usize, Arc<File>>>,
     status: Mutex<TaskStatus>,
+    // Asleep and held by no hart or queue, changed with `status` locked
+    parked: AtomicBool,
     is_exited: AtomicBool,
     exit_code: AtomicUsize,
     priority: Mutex<usize>,
@@ -49,6 +58,12 @@ impl TaskControlBlock {
         *self.status.lock() = status
     }
 
+    /// Run `f` on the status and the parked flag, locked against other
+    /// changes of both
+    pub fn with_status<R>(&self, f: impl FnOnce(&mut TaskStatus, &AtomicBool) -> R) -> R {
+        f(&mut self.status.lock(), &self.parked)
+    }
+
     pub fn get_context(&self) -> &'static UserContext {
         unsafe { &*(&**self.context.lock() as *const UserContext) }
     }
@@ -94,8 +109,8 @@ impl TaskControlBlock {
         *self.exception_entry.lock() = VirtAddr(entry);
     }
 
-    pub unsafe fn set_user_context(&self, context: usize) {
-        let context = unsafe { core::slice::from_raw_parts(context as *const usize, 34) };
+    /// Replace the user registers, `usstatus` and `sepc`
+    pub fn set_user_context(&self, context: &[usize; 34]) {
         unsafe {
             core::ptr::copy_nonoverlapping(
                 context.as_ptr(),
@@ -106,13 +121,16 @@ impl TaskControlBlock {
     }
 
     pub fn exit(&self) {
+        self.exit_with(self.get_context().uregs[10]);
+    }
+
+    pub fn exit_with(&self, code: usize) {
         if self
             .is_exited
             .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
         {
-            self.exit_code
-                .store(self.get_context().uregs[10], Ordering::Relaxed);
+            self.exit_code.store(code, Ordering::Relaxed);
         }
     }
 
@@ -132,14 +150,32 @@ impl TaskControlBlock {
         self.exit_code.load(Ordering::Relaxed)
     }
 
-    pub fn page_table(&self) -> PageTable {
-        unsafe { PageTable::from_ppn(self.memory.lock().page_table.ppn()) }
-    }
-
     pub fn memory(&self) -> &Mutex<UserSpace> {
         &self.memory
     }
 
+    /// Give `file` the lowest free descriptor
+    pub fn open_file(&self, file: Arc<File>) -> Result<usize, OsError> {
+        let mut files = self.files.lock();
+        let fd = (0..MAX_OPEN_FILES)
+            .find(|fd| !files.contains_key(fd))
+            .ok_or(OsError::MaxOpen)?;
+        files.insert(fd, file);
+        Ok(fd)
+    }
+
+    pub fn close_file(&self, fd: usize) -> Result<(), OsError> {
+        self.files
+            .lock()
+            .remove(&fd)
+            .map(|_| ())
+            .ok_or(OsError::InvalidParam)
+    }
+
+    pub fn file(&self, fd: usize) -> Option<Arc<File>> {
+        self.files.lock().get(&fd).cloned()
+    }
+
     pub fn get_task(self: Arc<TaskControlBlock>, pid: Pid) -> Option<Arc<TaskControlBlock>> {
         if pid == Pid(0) {
             return Some(self.clone());
@@ -159,15 +195,19 @@ impl TaskControlBlock {
 
 impl TaskControlBlock {
     pub fn new() -> Arc<Self> {
+        let pid = alloc_pid();
+        let memory = UserSpace::new(pid.pid());
         Arc::new(Self {
-            pid: alloc_pid(),
+            pid,
             parent: Mutex::new(None),
             exception_entry: Mutex::new(VirtAddr(0)),
             context: Mutex::new(Box::new(UserContext::default())),
             ipc_info: Mutex::new(IpcInfo::new()),
             children: Mutex::new(Vec::new()),
-            memory: Mutex::new(UserSpace::new()),
+            memory: Mutex::new(memory),
+            files: Mutex::new(BTreeMap::new()),
             status: Mutex::new(TaskStatus::Uninit),
+            parked: AtomicBool::new(false),
             is_exited: AtomicBool::new(false),
             exit_code: AtomicUsize::new(0),
             priority: Mutex::new(1),
@@ -176,19 +216,38 @@ impl TaskControlBlock {
         })
     }
 
-    pub fn init(self: Arc<Self>, elf: &[u8]) {
+    /// Load `elf` and set up the initial stack with `argv`, `envp` and the
+    /// auxiliary vector
+    pub fn init(
+        self: Arc<Self>,
+        elf: &'static [u8],
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<(), OsError> {
         let mut memory = self.memory.lock();
-        let entry = memory.map_elf(elf);
-        memory.init_heap(1);
+        let loaded = memory.map_elf(elf)?;
+        memory.init_heap();
+        let mut auxv = Vec::from([
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, loaded.entry),
+            (AT_PHENT, size_of::<ProgramHeader64>()),
+            (AT_PHNUM, loaded.phnum),
+            (AT_HART_COUNT, get_hart_count()),
+        ]);
+        if let Some(phdr) = loaded.phdr {
+            auxv.push((AT_PHDR, phdr));
+        }
+        let sp = memory.init_stack(argv, envp, &auxv)?;
         let mut context = self.context.lock();
-        context.sepc = entry;
-        context.uregs[2] = U_STACK_END;
+        context.sepc = loaded.entry;
+        context.uregs[2] = sp.0;
         let sstatus: usize;
         unsafe {
             asm!("csrr {0}, sstatus", out(reg) sstatus);
         }
         context.usstatus = sstatus;
         self.set_status(TaskStatus::Ready);
+        Ok(())
     }
 
     pub fn do_exit(&self) {
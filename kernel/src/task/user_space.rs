@@ -1,158 +1,759 @@
 use crate::{
+    Mutex,
     error::OsError,
-    mm::{addr::pa2kva, address_space::U_HEAP_BEG, consts::PAGE_SIZE},
+    mm::{
+        addr::pa2kva,
+        address_space::{
+            U_FILE_MAPPING_BEG, U_FILE_MAPPING_END, U_HEAP_BEG, U_HEAP_END, U_STACK_BEG,
+        },
+        consts::PAGE_SIZE,
+        paging::{
+            asid::{self, Asid},
+            tlb,
+        },
+    },
+    round_up,
 };
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use arch::tp;
 use bitflags::bitflags;
+use core::ops::Range;
 use log::trace;
 
 use crate::{
-    config::TASK_STACK_SIZE,
+    config::{
+        SWAP_CLUSTER, TASK_STACK_SIZE, USER_ARG_MAX, USER_ASLR, USER_MMAP_RANDOM, USER_STACK_RANDOM,
+    },
     mm::{
-        addr::{VirtAddr, VirtPageNum},
+        addr::{PhysAddr, VirtAddr, VirtPageNum},
         address_space::U_STACK_END,
+        file::File,
         frame::{self, FrameTracker},
-        paging::{page_table::PageTable, pte::PteFlags},
+        page::PageFlags,
+        paging::{
+            page_table::{PageSize, PageTable},
+            pte::PteFlags,
+        },
+        swap,
+    },
+    random,
+    task::{
+        elf::{AT_NULL, AT_RANDOM, Elf, LoadedElf},
+        image_cache::{self, Image},
+        pid::{Pid, alloc_pid},
     },
 };
 
 pub struct UserSpace {
+    // Task owning this address space, recorded in the reverse map of frames
+    pid: Pid,
     pub page_table: PageTable,
-    areas: BTreeMap<VirtPageNum, UserArea>,
+    asid: Asid,
+    // Harts which may hold translations of this address space in their TLB
+    active_harts: usize,
+    // Non-overlapping VMAs keyed by their first page
+    vmas: BTreeMap<VirtPageNum, Vma>,
+    // Program break, the end of the heap region
+    brk: VirtAddr,
+    // Maximum size of the stack, the page below it is kept as a guard page
+    stack_limit: usize,
+    // Initial stack pointer, the stack grows down from here
+    stack_top: VirtAddr,
+    // Where the search for free file mapping space starts
+    mmap_base: VirtPageNum,
 }
 
 impl UserSpace {
-    pub fn new() -> Self {
+    pub fn new(pid: Pid) -> Self {
         Self {
+            pid,
             page_table: PageTable::from_kernel_page_table(),
-            areas: BTreeMap::new(),
+            asid: Asid::new(),
+            active_harts: 0,
+            vmas: BTreeMap::new(),
+            brk: VirtAddr(U_HEAP_BEG),
+            stack_limit: TASK_STACK_SIZE,
+            stack_top: VirtAddr(U_STACK_END - random_pages(USER_STACK_RANDOM) * PAGE_SIZE),
+            mmap_base: VirtAddr(U_FILE_MAPPING_BEG).floor_page() + random_pages(USER_MMAP_RANDOM),
         }
     }
 
-    pub fn map_elf(&mut self, elf: &[u8]) -> usize {
-        let elf = xmas_elf::ElfFile::new(elf).expect("failed to parse ELF file");
-        for ph in elf.program_iter() {
-            if ph.get_type().expect("failed to get program header type")
-                != xmas_elf::program::Type::Load
-            {
-                continue;
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Switch the current hart to this address space
+    pub fn activate(&mut self) {
+        if asid::activate(self.page_table.ppn(), &mut self.asid) {
+            // No other hart can have cached translations tagged with a fresh ASID
+            self.active_harts = 0;
+        }
+        self.active_harts |= 1 << tp();
+    }
+
+    /// Flush `vpn` on every hart that has run this address space. Returns after
+    /// all of them are done, so the old frame can be released afterwards.
+    fn flush_tlb(&self, vpn: VirtPageNum) {
+        self.flush_tlb_range(vpn, vpn + 1);
+    }
+
+    fn flush_tlb_range(&self, start: VirtPageNum, end: VirtPageNum) {
+        tlb::shootdown(
+            self.active_harts,
+            self.asid.value(),
+            VirtAddr::from(start).0,
+            (end.0 - start.0) * PAGE_SIZE,
+        );
+    }
+
+    /// The VMA containing `vpn`
+    fn vma(&self, vpn: VirtPageNum) -> Option<&Vma> {
+        self.vmas
+            .range(..=vpn)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(vpn))
+    }
+
+    fn vma_mut(vmas: &mut BTreeMap<VirtPageNum, Vma>, vpn: VirtPageNum) -> Option<&mut Vma> {
+        vmas.range_mut(..=vpn)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(vpn))
+    }
+
+    /// Whether any VMA overlaps `start..end`
+    fn overlaps(&self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        self.vmas
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, vma)| vma.end > start)
+    }
+
+    /// VMAs overlapping `start..end`, in ascending order
+    fn vmas_in(&self, start: VirtPageNum, end: VirtPageNum) -> impl Iterator<Item = &Vma> {
+        let from = self
+            .vmas
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(&vpn, _)| vpn);
+        self.vmas
+            .range(from..end)
+            .map(|(_, vma)| vma)
+            .filter(move |vma| vma.end > start)
+    }
+
+    /// Parts of `start..end` not covered by any VMA
+    fn gaps(&self, start: VirtPageNum, end: VirtPageNum) -> Vec<(VirtPageNum, VirtPageNum)> {
+        let mut gaps = Vec::new();
+        let mut cursor = start;
+        for vma in self.vmas_in(start, end) {
+            if vma.start > cursor {
+                gaps.push((cursor, vma.start));
             }
-            let offset = ph.offset() as usize;
-            let start = VirtAddr(ph.virtual_addr() as usize);
-            let size = ph.mem_size() as usize;
-            let perm = ph.flags().into();
-            let mut vpn = start.floor_page();
-            for i in (0..size).step_by(PAGE_SIZE) {
-                let mut area = UserArea::new(UserAreaType::Framed, perm, vpn);
-                area.map(&mut self.page_table)
-                    .expect("failed to map user area");
-                area.copy_data(
-                    &mut self.page_table,
-                    &elf.input[offset + i..(offset + i + PAGE_SIZE).min(offset + size)],
-                );
-                self.areas.insert(vpn, area);
-                vpn += 1;
+            cursor = cursor.max(vma.end);
+        }
+        if cursor < end {
+            gaps.push((cursor, end));
+        }
+        gaps
+    }
+
+    /// Register `vma`, which must not overlap others. It is merged with the
+    /// neighbours it continues.
+    fn insert(&mut self, mut vma: Vma) {
+        debug_assert!(!self.overlaps(vma.start, vma.end));
+        if self
+            .vmas
+            .get(&vma.end)
+            .is_some_and(|next| vma.can_merge(next))
+        {
+            let next = self.vmas.remove(&vma.end).unwrap();
+            vma.append(next);
+        }
+        if let Some((_, prev)) = self.vmas.range_mut(..vma.start).next_back()
+            && prev.end == vma.start
+            && prev.can_merge(&vma)
+        {
+            prev.append(vma);
+            return;
+        }
+        self.vmas.insert(vma.start, vma);
+    }
+
+    /// Remove `start..end` from the VMAs, splitting those which cross its
+    /// ends. Returns whether anything was mapped there.
+    fn unmap_range(&mut self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        let starts: Vec<_> = self.vmas_in(start, end).map(|vma| vma.start).collect();
+        for vpn in &starts {
+            let mut vma = self.vmas.remove(vpn).unwrap();
+            if vma.start < start {
+                let rest = vma.split_off(start);
+                self.vmas.insert(vma.start, vma);
+                vma = rest;
+            }
+            if vma.end > end {
+                let rest = vma.split_off(end);
+                self.vmas.insert(rest.start, rest);
+            }
+            self.release(vma);
+        }
+        !starts.is_empty()
+    }
+
+    /// Register the segments of the executable `image` and map the top page
+    /// of the stack. Segment pages are loaded on their first access, see
+    /// `populate`. Returns where the entry point and program headers ended
+    /// up.
+    pub fn map_elf(&mut self, image: &'static [u8]) -> Result<LoadedElf, OsError> {
+        let image = Arc::new(Image::new(Elf::parse(image)?));
+        let elf = image.elf();
+        let bias = elf.load_bias();
+        image_cache::prune();
+        for segment in &elf.segments {
+            let vaddr = segment.vaddr.wrapping_add(bias);
+            let start = VirtAddr(vaddr).floor_page();
+            let end = VirtAddr(vaddr + segment.mem_size).ceil_page();
+            // The first page may be shared with the previous segment
+            let start = if self.vma(start).is_some() {
+                start + 1
+            } else {
+                start
+            };
+            if start < end {
+                let backing = VmaBacking::Image {
+                    image: image.clone(),
+                    bias,
+                };
+                self.insert(Vma::new(
+                    start,
+                    end,
+                    segment.perm,
+                    backing,
+                    VmaFlags::empty(),
+                ));
             }
         }
-        // alloc stack
+        // The rest of the stack grows on demand, see `grow_stack`
         trace!("allocating stack");
-        (U_STACK_END - TASK_STACK_SIZE..U_STACK_END)
-            .step_by(PAGE_SIZE)
-            .for_each(|va| {
-                let vpn = VirtAddr(va).floor_page();
-                let mut area =
-                    UserArea::new(UserAreaType::Framed, UserAreaPerm::R | UserAreaPerm::W, vpn);
-                if va == U_STACK_END - PAGE_SIZE {
-                    area.map(&mut self.page_table).unwrap();
-                }
-                self.areas.insert(vpn, area);
-            });
-        elf.header.pt2.entry_point() as usize
+        let vpn = (self.stack_top - PAGE_SIZE).floor_page();
+        let mut vma = Vma::new(
+            vpn,
+            vpn + 1,
+            UserAreaPerm::R | UserAreaPerm::W,
+            VmaBacking::Anonymous,
+            VmaFlags::STACK,
+        );
+        let frame = frame::alloc()?;
+        vma.map(&mut self.page_table, self.pid, vpn, Arc::new(frame));
+        self.insert(vma);
+        Ok(LoadedElf {
+            entry: elf.entry.wrapping_add(bias),
+            phdr: elf.phdr.map(|phdr| phdr.wrapping_add(bias)),
+            phnum: elf.phnum,
+        })
     }
 
-    pub fn init_heap(&mut self, page_count: usize) {
-        let heap_start = VirtAddr(U_HEAP_BEG);
-        let mut vpn = heap_start.floor_page();
-        for _ in 0..page_count {
-            let area = UserArea::new(UserAreaType::Framed, UserAreaPerm::R | UserAreaPerm::W, vpn);
-            // area.map(&mut self.page_table)
-            //     .expect("failed to map user area");
-            self.areas.insert(vpn, area);
-            vpn += 1;
+    /// Lay out the initial stack as the psABI describes it: argc at the stack
+    /// pointer, then the NULL-terminated argv and envp arrays and the
+    /// auxiliary vector, with the strings and 16 random bytes for `AT_RANDOM`
+    /// above them. `auxv` is extended with `AT_RANDOM` and `AT_NULL`. Returns
+    /// the stack pointer.
+    pub fn init_stack(
+        &mut self,
+        argv: &[&str],
+        envp: &[&str],
+        auxv: &[(usize, usize)],
+    ) -> Result<VirtAddr, OsError> {
+        let top = self.stack_top.0;
+        let strings: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+        let random_at = (top - strings - 16) & !15;
+        let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 2);
+        let sp = (random_at - words * size_of::<usize>()) & !15;
+        if top - sp > USER_ARG_MAX {
+            return Err(OsError::InvalidParam);
+        }
+
+        let mut stack = alloc::vec![0u8; top - sp];
+        let mut words_at = 0;
+        let mut push = |stack: &mut Vec<u8>, word: usize| {
+            stack[words_at..words_at + size_of::<usize>()].copy_from_slice(&word.to_le_bytes());
+            words_at += size_of::<usize>();
+        };
+        let mut string_at = top - strings;
+        push(&mut stack, argv.len());
+        for list in [argv, envp] {
+            for s in list {
+                let offset = string_at - sp;
+                stack[offset..offset + s.len()].copy_from_slice(s.as_bytes());
+                push(&mut stack, string_at);
+                string_at += s.len() + 1;
+            }
+            push(&mut stack, 0);
+        }
+        for &(ty, value) in auxv.iter().chain(&[(AT_RANDOM, random_at), (AT_NULL, 0)]) {
+            push(&mut stack, ty);
+            push(&mut stack, value);
+        }
+        let offset = random_at - sp;
+        stack[offset..offset + 8].copy_from_slice(&random::random().to_le_bytes());
+        stack[offset + 8..offset + 16].copy_from_slice(&random::random().to_le_bytes());
+
+        // Pages below the first one are grown into like any stack access
+        let mut copied = 0;
+        while copied < stack.len() {
+            let va = VirtAddr(sp + copied);
+            let len = usize::min(PAGE_SIZE - va.0 % PAGE_SIZE, stack.len() - copied);
+            // Nothing waits for the lock of a space which is not running yet,
+            // so the swap I/O is done right away
+            while let Err(e) = self.fault_in(va, UserPageFaultType::Write) {
+                match e {
+                    UserPageFaultError::SwapIo => swap::run_io()?,
+                    e => return Err(e.into()),
+                }
+            }
+            copy_data(&self.page_table, va, &stack[copied..copied + len]);
+            copied += len;
         }
+        Ok(VirtAddr(sp))
     }
 
-    pub fn check_perm(&self, vpn: VirtPageNum, perm: UserAreaPerm) -> bool {
-        if let Some(area) = self.areas.get(&vpn) {
-            area.perm().contains(perm)
+    pub fn init_heap(&mut self) {
+        self.brk = VirtAddr(U_HEAP_BEG);
+    }
+
+    pub fn brk(&self) -> VirtAddr {
+        self.brk
+    }
+
+    /// Move the program break to `new_brk`, registering or releasing heap pages.
+    pub fn set_brk(&mut self, new_brk: VirtAddr) -> Result<VirtAddr, OsError> {
+        if !(U_HEAP_BEG..=U_HEAP_END).contains(&new_brk.0) {
+            return Err(OsError::InvalidParam);
+        }
+        let old_end = self.brk.ceil_page();
+        let new_end = new_brk.ceil_page();
+        if new_end > old_end {
+            // Pages are registered lazily and mapped on first access
+            for (start, end) in self.gaps(old_end, new_end) {
+                self.insert(Vma::new(
+                    start,
+                    end,
+                    UserAreaPerm::R | UserAreaPerm::W,
+                    VmaBacking::Anonymous,
+                    VmaFlags::HEAP,
+                ));
+            }
         } else {
-            false
+            self.unmap_range(new_end, old_end);
         }
+        self.brk = new_brk;
+        Ok(new_brk)
+    }
+
+    pub fn stack_limit(&self) -> usize {
+        self.stack_limit
+    }
+
+    /// Change the maximum size of the stack, returning the one applied. The
+    /// stack cannot be limited below the pages it already uses.
+    pub fn set_stack_limit(&mut self, limit: usize) -> Result<usize, OsError> {
+        // Leave room for the guard page at the bottom of the stack region
+        let limit = round_up!(limit, PAGE_SIZE)
+            .clamp(PAGE_SIZE, self.stack_top.0 - U_STACK_BEG - PAGE_SIZE);
+        let guard = (self.stack_top - limit).floor_page() - 1;
+        if self.vma(guard).is_some() {
+            return Err(OsError::InvalidParam);
+        }
+        self.stack_limit = limit;
+        Ok(limit)
+    }
+
+    fn stack_guard(&self) -> VirtPageNum {
+        (self.stack_top - self.stack_limit).floor_page() - 1
+    }
+
+    /// Extend the stack down to `vpn` on its first access. Touching the guard
+    /// page, or anything below it, is reported as a stack overflow.
+    fn grow_stack(&mut self, vpn: VirtPageNum) -> Result<(), UserPageFaultError> {
+        if vpn <= self.stack_guard() {
+            return Err(UserPageFaultError::StackOverflow);
+        }
+        trace!("growing user stack to {}", vpn);
+        let top = self.stack_top.floor_page();
+        let end = self
+            .vmas
+            .range(vpn..)
+            .next()
+            .map_or(top, |(&start, _)| start.min(top));
+        self.insert(Vma::new(
+            vpn,
+            end,
+            UserAreaPerm::R | UserAreaPerm::W,
+            VmaBacking::Anonymous,
+            VmaFlags::STACK,
+        ));
+        Ok(())
+    }
+
+    pub fn check_perm(&self, vpn: VirtPageNum, perm: UserAreaPerm) -> bool {
+        self.vma(vpn).is_some_and(|vma| vma.perm.contains(perm))
     }
 
     pub fn alloc(&mut self, vpn: VirtPageNum, perm: UserAreaPerm) -> Result<(), OsError> {
-        if self.areas.contains_key(&vpn) {
+        if vpn == self.stack_guard() {
+            return Err(OsError::InvalidParam);
+        }
+        if self.vma(vpn).is_some() {
             return Ok(());
         }
-        let area = UserArea::new(UserAreaType::Framed, perm, vpn);
-        // area.map(&mut self.page_table)?;
-        self.areas.insert(vpn, area);
+        self.insert(Vma::new(
+            vpn,
+            vpn + 1,
+            perm,
+            VmaBacking::Anonymous,
+            VmaFlags::empty(),
+        ));
         Ok(())
     }
 
-    pub fn handle_page_fault(&mut self, stval: usize, ty: UserPageFaultType) -> Result<(), ()> {
+    pub fn handle_page_fault(
+        &mut self,
+        stval: usize,
+        ty: UserPageFaultType,
+    ) -> Result<(), UserPageFaultError> {
         let vpn = VirtAddr(stval).floor_page();
         let perm = match ty {
             UserPageFaultType::Read => UserAreaPerm::R,
             UserPageFaultType::Write => UserAreaPerm::R | UserAreaPerm::W,
             UserPageFaultType::Execute => UserAreaPerm::R | UserAreaPerm::X,
         };
-        // TODO This is not verified
-        if let Some(area) = self.areas.get_mut(&vpn) {
-            if ty == UserPageFaultType::Write && area.cow {
-                let frame = area.get_frame();
-                if Arc::strong_count(&frame) > 1 {
-                    let new_frame = frame::alloc().map_err(|_| ())?;
-                    unsafe {
-                        let src = pa2kva(frame.ppn.into()).as_ptr::<usize>();
-                        let dst = pa2kva(new_frame.ppn.into()).as_mut_ptr::<usize>();
-                        core::ptr::copy_nonoverlapping(src, dst, PAGE_SIZE);
-                    }
-                    let mut new_area = UserArea::new_with_frame(
-                        UserAreaType::Framed,
-                        perm,
-                        vpn,
-                        Arc::new(new_frame),
-                    );
-                    new_area.map(&mut self.page_table).map_err(|_| ())?;
-                } else {
-                    // just remove COW flag
-                    area.cow = false;
-                    area.map(&mut self.page_table).map_err(|_| ())?;
+        if self.vma(vpn).is_none() && (U_STACK_BEG..self.stack_top.0).contains(&stval) {
+            self.grow_stack(vpn)?;
+        }
+        let Some(vma) = self.vma(vpn) else {
+            return Err(UserPageFaultError::Unmapped);
+        };
+        let present = vma.frame(vpn).is_some();
+        if !self.check_perm(vpn, perm) {
+            return Err(UserPageFaultError::Permission);
+        }
+        let cow = present
+            && self
+                .page_table
+                .find(vpn)
+                .is_some_and(|(pte, _)| pte.flags().contains(PteFlags::COW));
+        if ty == UserPageFaultType::Write && cow {
+            self.break_cow(vpn)?;
+        } else if present {
+            // The translation is stale or the hardware does not update the
+            // accessed and dirty bits itself
+            if let Some((pte, _)) = self.page_table.find(vpn) {
+                pte.set_flags(PteFlags::A);
+                if ty == UserPageFaultType::Write {
+                    pte.set_flags(PteFlags::D);
                 }
-            } else if self.check_perm(vpn, perm) {
-                self.areas
-                    .get_mut(&vpn)
-                    .unwrap()
-                    .map(&mut self.page_table)
-                    .map_err(|_| ())?;
             }
-            Ok(())
+            self.flush_tlb(vpn);
+        } else {
+            self.populate(vpn, ty == UserPageFaultType::Write)?;
+        }
+        Ok(())
+    }
+
+    /// Give the copy-on-write page `vpn` a frame of its own
+    fn break_cow(&mut self, vpn: VirtPageNum) -> Result<(), UserPageFaultError> {
+        let vma = Self::vma_mut(&mut self.vmas, vpn).unwrap();
+        let frame = vma.unmap(&mut self.page_table, self.pid, vpn).unwrap();
+        let private = if frame::is_zero_frame(frame.ppn) {
+            // Fresh frames are zeroed already
+            self.alloc_frame().map(Arc::new)
+        } else if frame.page().map_count() > 0 || Arc::strong_count(&frame) > 1 {
+            // Still shared with another space or the page cache, copy to
+            // a private frame
+            self.alloc_frame().map(|new_frame| {
+                copy_frame(&frame, &new_frame);
+                Arc::new(new_frame)
+            })
         } else {
-            Err(())
+            // The last user of the frame, just remove COW flag
+            Ok(frame.clone())
+        };
+        let vma = Self::vma_mut(&mut self.vmas, vpn).unwrap();
+        let private = match private {
+            Ok(private) => private,
+            Err(e) => {
+                // Keep the shared mapping so the page is not lost
+                vma.map_cow(&mut self.page_table, self.pid, vpn, frame);
+                return Err(e);
+            }
+        };
+        vma.map(&mut self.page_table, self.pid, vpn, private);
+        self.flush_tlb(vpn);
+        Ok(())
+    }
+
+    /// Make the page holding `va` accessible for a `ty` access before the
+    /// kernel touches it, as a fault taken by user code would
+    pub fn fault_in(
+        &mut self,
+        va: VirtAddr,
+        ty: UserPageFaultType,
+    ) -> Result<(), UserPageFaultError> {
+        if let Some((pte, _)) = self.page_table.find(va.floor_page()) {
+            let present = match ty {
+                UserPageFaultType::Read => pte.readable() && pte.accessed(),
+                UserPageFaultType::Write => pte.writable() && pte.dirty(),
+                UserPageFaultType::Execute => pte.executable() && pte.accessed(),
+            };
+            if pte.user() && present {
+                return Ok(());
+            }
         }
+        self.handle_page_fault(va.0, ty)
     }
 
-    pub fn find_frame(&mut self, vpn: VirtPageNum) -> Result<Arc<FrameTracker>, OsError> {
-        if let Some(area) = self.areas.get_mut(&vpn) {
-            if !area.is_mapped() {
-                area.map(&mut self.page_table)?;
+    /// Back the page `vpn` of a VMA by a frame, taking it from the swap cache
+    /// if it was swapped out. Anonymous pages share the zero frame and private
+    /// file pages share the page cache until the first `write`.
+    fn populate(&mut self, vpn: VirtPageNum, write: bool) -> Result<(), UserPageFaultError> {
+        if let Some(slot) = self.page_table.swap_slot(vpn) {
+            let frame = swap::swap_in(slot, || self.alloc_frame())?;
+            self.page_table.clear_swap(vpn);
+            swap::free_slot(slot);
+            self.map_private(vpn, frame);
+            return Ok(());
+        }
+        if let Some((image, index, bias)) = self.vma(vpn).unwrap().image_page(vpn) {
+            // Pages past the initialized part are zero-filled like anonymous
+            // memory
+            if image.elf().is_initialized(index * PAGE_SIZE) || image.is_relocated(index) {
+                return self.populate_image(vpn, image.clone(), index, bias, write);
             }
-            Ok(area.get_frame())
+        }
+        match self.vma(vpn).unwrap().file_page(vpn) {
+            None if !write => self.map_cow(vpn, frame::zero_frame()),
+            None => {
+                let frame = self.alloc_frame()?;
+                self.map_private(vpn, Arc::new(frame));
+            }
+            Some((file, index, shared)) => {
+                let file = file.clone();
+                let cached = file.page(index, || self.alloc_frame())?;
+                if shared {
+                    self.map_private(vpn, cached);
+                } else if write {
+                    let frame = self.alloc_frame()?;
+                    copy_frame(&cached, &frame);
+                    self.map_private(vpn, Arc::new(frame));
+                } else {
+                    self.map_cow(vpn, cached);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Back `vpn` by page `index` of `image`. Unless written to or relocated,
+    /// the page is shared with every task running the image.
+    fn populate_image(
+        &mut self,
+        vpn: VirtPageNum,
+        image: Arc<Image>,
+        index: usize,
+        bias: usize,
+        write: bool,
+    ) -> Result<(), UserPageFaultError> {
+        let shared = image
+            .shared_page(index)
+            .map_err(|_| UserPageFaultError::NoMem)?;
+        if write || image.is_relocated(index) {
+            let frame = self.alloc_frame()?;
+            copy_frame(&shared, &frame);
+            image.relocate(index, bias, unsafe {
+                PhysAddr::from(frame.ppn).as_mut_page_slice()
+            });
+            frame.page().clear_flags(PageFlags::ZEROED);
+            self.map_private(vpn, Arc::new(frame));
+        } else if self.vma(vpn).unwrap().perm.contains(UserAreaPerm::W) {
+            self.map_cow(vpn, shared);
         } else {
-            Err(OsError::InvalidParam)
+            self.map_private(vpn, shared);
         }
+        Ok(())
+    }
+
+    fn map_private(&mut self, vpn: VirtPageNum, frame: Arc<FrameTracker>) {
+        let vma = Self::vma_mut(&mut self.vmas, vpn).unwrap();
+        vma.map(&mut self.page_table, self.pid, vpn, frame);
+    }
+
+    fn map_cow(&mut self, vpn: VirtPageNum, frame: Arc<FrameTracker>) {
+        let vma = Self::vma_mut(&mut self.vmas, vpn).unwrap();
+        vma.map_cow(&mut self.page_table, self.pid, vpn, frame);
+    }
+
+    /// Allocate a frame. If memory is exhausted, pages are queued to be
+    /// swapped out and `SwapIo` is returned, the allocation has to be retried
+    /// after the I/O.
+    fn alloc_frame(&mut self) -> Result<FrameTracker, UserPageFaultError> {
+        frame::alloc().map_err(|_| {
+            if swap::reclaim(self, SWAP_CLUSTER) == 0 {
+                return UserPageFaultError::NoMem;
+            }
+            UserPageFaultError::SwapIo
+        })
+    }
+
+    /// Evict `vpn` if it has not been accessed recently, returns whether it
+    /// was. Pages of the page cache are unmapped, to be dropped from the cache
+    /// once no one maps them and they are written back. Other pages are queued
+    /// to be written to swap. Accessed pages get a second chance when
+    /// `second_chance` is set, otherwise only clean pages are taken.
+    pub fn try_evict(&mut self, vpn: VirtPageNum, second_chance: bool) -> bool {
+        let Some(vma) = self.vma(vpn) else {
+            return false;
+        };
+        let Some(frame) = vma.frame(vpn) else {
+            return false;
+        };
+        let cached = vma
+            .file_page(vpn)
+            .filter(|(file, index, _)| file.caches(*index, frame))
+            .map(|(file, index, shared)| (file.clone(), index, shared));
+        if cached.is_none() && Arc::strong_count(frame) > 1 {
+            return false;
+        }
+        let Some((pte, PageSize::Size4KiB)) = self.page_table.find(vpn) else {
+            return false;
+        };
+        if pte.accessed() {
+            if second_chance {
+                pte.clear_flags(PteFlags::A);
+                self.flush_tlb(vpn);
+            }
+            return false;
+        }
+        if pte.dirty() && !second_chance {
+            return false;
+        }
+        if let Some((file, index, shared)) = cached {
+            if shared && pte.dirty() {
+                file.mark_dirty(index);
+            }
+            let vma = Self::vma_mut(&mut self.vmas, vpn).unwrap();
+            let _frame = vma.unmap(&mut self.page_table, self.pid, vpn);
+            self.flush_tlb(vpn);
+            return true;
+        }
+        let Some(slot) = swap::alloc_slot() else {
+            return false;
+        };
+        let vma = Self::vma_mut(&mut self.vmas, vpn).unwrap();
+        let frame = vma.swap_out(&mut self.page_table, self.pid, vpn, slot);
+        self.flush_tlb(vpn);
+        swap::swap_out(slot, frame);
+        true
+    }
+
+    /// Mark the pages of shared file mappings in `start..end` which were
+    /// written to through `vma` dirty in the page cache. Returns the file
+    /// and the range of its pages to write back, which `swap::run_io` does
+    /// otherwise.
+    fn write_back(
+        &self,
+        vma: &Vma,
+        start: VirtPageNum,
+        end: VirtPageNum,
+    ) -> Option<(Arc<File>, Range<usize>)> {
+        let (start, end) = (start.max(vma.start), end.min(vma.end));
+        if start >= end {
+            return None;
+        }
+        let Some((file, first, true)) = vma.file_page(start) else {
+            return None;
+        };
+        for &vpn in vma.pages.range(start..end).map(|(vpn, _)| vpn) {
+            let Some((pte, _)) = self.page_table.find(vpn) else {
+                continue;
+            };
+            if vma.frame(vpn).is_some() && pte.dirty() {
+                pte.clear_flags(PteFlags::D);
+                self.flush_tlb(vpn);
+                file.mark_dirty(first + (vpn.0 - start.0));
+            }
+        }
+        Some((file.clone(), first..first + (end.0 - start.0)))
+    }
+
+    /// Drop a removed VMA, releasing its frames and swap slots
+    fn release(&mut self, mut vma: Vma) {
+        self.write_back(&vma, vma.start, vma.end);
+        let vpns: Vec<_> = vma.pages.keys().copied().collect();
+        let mut frames = Vec::new();
+        for vpn in vpns {
+            match vma.unmap(&mut self.page_table, self.pid, vpn) {
+                Some(frame) => frames.push(frame),
+                None => {
+                    if let Some(slot) = self.page_table.swap_slot(vpn) {
+                        self.page_table.clear_swap(vpn);
+                        swap::free_slot(slot);
+                    }
+                }
+            }
+        }
+        if !frames.is_empty() {
+            self.flush_tlb_range(vma.start, vma.end);
+        }
+    }
+
+    /// The frame backing `vpn`, to be mapped into another space with any
+    /// permission. Copy-on-write pages, which may be the zero frame or shared
+    /// with the image or page cache, get a private frame first. So do
+    /// read-only pages shared with the image cache or another space, which
+    /// this space maps without COW.
+    pub fn find_frame(
+        &mut self,
+        vpn: VirtPageNum,
+    ) -> Result<Arc<FrameTracker>, UserPageFaultError> {
+        let Some(vma) = self.vma(vpn) else {
+            return Err(UserPageFaultError::Unmapped);
+        };
+        if vma.file_page(vpn).is_some_and(|(_, _, shared)| shared) {
+            // Writes through another space would never reach the file
+            return Err(UserPageFaultError::Permission);
+        }
+        if vma.frame(vpn).is_none() {
+            self.populate(vpn, true)?;
+        }
+        if self
+            .page_table
+            .find(vpn)
+            .is_some_and(|(pte, _)| pte.flags().contains(PteFlags::COW))
+        {
+            self.break_cow(vpn)?;
+        }
+        let vma = self.vma(vpn).unwrap();
+        let frame = vma.frame(vpn).unwrap().clone();
+        let cached = vma
+            .image_page(vpn)
+            .is_some_and(|(image, index, _)| image.caches(index, &frame));
+        // Held by the VMA and `frame`
+        let shared = frame.page().map_count() > 1 || Arc::strong_count(&frame) > 2;
+        if vma.perm.contains(UserAreaPerm::W) || !(cached || shared) {
+            return Ok(frame);
+        }
+        let private = Arc::new(self.alloc_frame()?);
+        copy_frame(&frame, &private);
+        private.page().clear_flags(PageFlags::ZEROED);
+        let vma = Self::vma_mut(&mut self.vmas, vpn).unwrap();
+        vma.unmap(&mut self.page_table, self.pid, vpn);
+        vma.map(&mut self.page_table, self.pid, vpn, private.clone());
+        self.flush_tlb(vpn);
+        Ok(private)
     }
 
     pub fn map(
@@ -161,46 +762,183 @@ impl UserSpace {
         frame: Arc<FrameTracker>,
         perm: UserAreaPerm,
     ) -> Result<(), OsError> {
-        let mut area = UserArea::new_with_frame(UserAreaType::Framed, perm, vpn, frame);
-        area.map(&mut self.page_table)?;
-        self.areas.insert(vpn, area);
+        if vpn == self.stack_guard() {
+            return Err(OsError::InvalidParam);
+        }
+        self.unmap_range(vpn, vpn + 1);
+        let mut vma = Vma::new(vpn, vpn + 1, perm, VmaBacking::Anonymous, VmaFlags::empty());
+        vma.map(&mut self.page_table, self.pid, vpn, frame);
+        self.insert(vma);
         Ok(())
     }
 
     pub fn unmap(&mut self, vpn: VirtPageNum) -> Result<(), OsError> {
-        if let Some(area) = self.areas.get_mut(&vpn) {
-            area.unmap(&mut self.page_table);
-            self.areas.remove(&vpn);
+        if self.unmap_range(vpn, vpn + 1) {
             Ok(())
         } else {
             Err(OsError::InvalidParam)
         }
     }
 
-    pub fn fork(&mut self) -> Self {
-        let mut new_space = UserSpace::new();
-        for (vpn, area) in self.areas.iter_mut() {
-            if area.is_mapped() {
-                // Copy-on-write
-                let frame = area.get_frame();
-                let mut new_area = area.clone();
-                area.unmap(&mut self.page_table);
-                area.map_with_frame_cow(&mut self.page_table, frame.clone())
-                    .unwrap();
-                new_area
-                    .map_with_frame_cow(&mut new_space.page_table, frame)
-                    .unwrap();
-                new_space.areas.insert(*vpn, new_area);
-            } else {
-                // Could be directly cloned as the area is not mapped
-                new_space.areas.insert(*vpn, area.clone());
+    /// Map `pages` pages of `file` starting at page `first_page`, at `va` or
+    /// anywhere in the file mapping region if it is `None`. Pages are read in
+    /// on first access.
+    pub fn mmap(
+        &mut self,
+        va: Option<VirtAddr>,
+        pages: usize,
+        perm: UserAreaPerm,
+        file: Arc<File>,
+        first_page: usize,
+        shared: bool,
+    ) -> Result<VirtAddr, OsError> {
+        if pages == 0 || first_page + pages > file.pages() {
+            return Err(OsError::InvalidParam);
+        }
+        let start = match va {
+            Some(va) => {
+                let start = va.floor_page();
+                let end = VirtAddr(U_FILE_MAPPING_END).floor_page();
+                if va.0 % PAGE_SIZE != 0
+                    || va.0 < U_FILE_MAPPING_BEG
+                    || start.0 + pages > end.0
+                    || self.overlaps(start, start + pages)
+                {
+                    return Err(OsError::InvalidParam);
+                }
+                start
             }
+            None => self.find_free(pages).ok_or(OsError::NoMem)?,
+        };
+        let backing = VmaBacking::File {
+            file,
+            offset: first_page,
+            shared,
+        };
+        self.insert(Vma::new(
+            start,
+            start + pages,
+            perm,
+            backing,
+            VmaFlags::empty(),
+        ));
+        Ok(VirtAddr::from(start))
+    }
+
+    /// First fit for `pages` unregistered pages in the file mapping region,
+    /// searching from the mmap base before falling back to the whole region
+    fn find_free(&self, pages: usize) -> Option<VirtPageNum> {
+        let start = VirtAddr(U_FILE_MAPPING_BEG).floor_page();
+        let end = VirtAddr(U_FILE_MAPPING_END).floor_page();
+        [self.mmap_base, start].into_iter().find_map(|start| {
+            self.gaps(start, end)
+                .into_iter()
+                .find(|(start, end)| end.0 - start.0 >= pages)
+                .map(|(start, _)| start)
+        })
+    }
+
+    /// Mark the dirty pages of shared file mappings in `start..end`, returns
+    /// the file ranges to write back once this space is unlocked
+    pub fn msync(&self, start: VirtPageNum, end: VirtPageNum) -> Vec<(Arc<File>, Range<usize>)> {
+        self.vmas_in(start, end)
+            .filter_map(|vma| self.write_back(vma, start, end))
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn fork(&mut self, pid: Pid) -> Self {
+        let mut new_space = UserSpace::new(pid);
+        new_space.brk = self.brk;
+        new_space.stack_limit = self.stack_limit;
+        new_space.stack_top = self.stack_top;
+        new_space.mmap_base = self.mmap_base;
+        for vma in self.vmas.values_mut() {
+            let mut new_vma =
+                Vma::new(vma.start, vma.end, vma.perm, vma.backing.clone(), vma.flags);
+            let shared = matches!(vma.backing, VmaBacking::File { shared: true, .. });
+            let vpns: Vec<_> = vma.pages.keys().copied().collect();
+            for vpn in vpns {
+                match vma.pages[&vpn].clone() {
+                    VmaPage::Present(frame) if shared => {
+                        // Both spaces keep writing to the page cache
+                        new_vma.map(&mut new_space.page_table, new_space.pid, vpn, frame);
+                    }
+                    VmaPage::Present(frame) => {
+                        // Copy-on-write
+                        vma.unmap(&mut self.page_table, self.pid, vpn);
+                        vma.map_cow(&mut self.page_table, self.pid, vpn, frame.clone());
+                        new_vma.map_cow(&mut new_space.page_table, new_space.pid, vpn, frame);
+                    }
+                    VmaPage::Swapped => {
+                        // Both spaces refer to the same swap slot
+                        if let Some(slot) = self.page_table.swap_slot(vpn) {
+                            swap::dup_slot(slot);
+                            new_space.page_table.set_swap(vpn, slot);
+                            new_vma.pages.insert(vpn, VmaPage::Swapped);
+                        }
+                    }
+                }
+            }
+            new_space.vmas.insert(new_vma.start, new_vma);
         }
-        riscv::asm::sfence_vma_all();
+        // Writable translations of this space are now stale on every hart
+        // which ran it, only those tagged with its ASID are flushed
+        tlb::shootdown(self.active_harts, self.asid.value(), 0, usize::MAX);
         new_space
     }
 }
 
+/// A random number of pages spanning less than `range` bytes, 0 without ASLR
+fn random_pages(range: usize) -> usize {
+    if USER_ASLR {
+        random::below(range / PAGE_SIZE)
+    } else {
+        0
+    }
+}
+
+fn copy_frame(src: &FrameTracker, dst: &FrameTracker) {
+    unsafe {
+        let src = pa2kva(src.ppn.into()).as_ptr::<u8>();
+        let dst = pa2kva(dst.ppn.into()).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(src, dst, PAGE_SIZE);
+    }
+}
+
+/// Copy `data` to `va` through the kernel mapping of its page, which must
+/// hold all of it
+fn copy_data(page_table: &PageTable, va: VirtAddr, data: &[u8]) {
+    let page = unsafe {
+        page_table
+            .query(va.floor_page())
+            .unwrap()
+            .pa()
+            .as_mut_page_slice()
+    };
+    page[va.0 % PAGE_SIZE..][..data.len()].copy_from_slice(data);
+}
+
+impl Drop for UserSpace {
+    fn drop(&mut self) {
+        // Remove this space from the reverse map of every frame it still maps
+        // and give back its swap slots
+        for vma in self.vmas.values() {
+            self.write_back(vma, vma.start, vma.end);
+        }
+        for vma in self.vmas.values_mut() {
+            let vpns: Vec<_> = vma.pages.keys().copied().collect();
+            for vpn in vpns {
+                if vma.unmap(&mut self.page_table, self.pid, vpn).is_none()
+                    && let Some(slot) = self.page_table.swap_slot(vpn)
+                {
+                    swap::free_slot(slot);
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum UserPageFaultType {
     Read,
@@ -208,8 +946,158 @@ pub enum UserPageFaultType {
     Execute,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UserPageFaultError {
+    /// No area is registered at the faulting address
+    Unmapped,
+    /// The area does not allow this kind of access
+    Permission,
+    /// The stack ran into its guard page
+    StackOverflow,
+    /// Out of frames while populating the page
+    NoMem,
+    /// Reading the page back from swap failed
+    Io,
+    /// Swap or page cache I/O is needed first, see `with_swap_io`
+    SwapIo,
+}
+
+impl From<UserPageFaultError> for OsError {
+    fn from(e: UserPageFaultError) -> Self {
+        match e {
+            UserPageFaultError::NoMem => OsError::NoMem,
+            UserPageFaultError::Io | UserPageFaultError::SwapIo => OsError::NoDisk,
+            _ => OsError::InvalidParam,
+        }
+    }
+}
+
+/// Run `f` on `memory` until it needs no more swap I/O, which is done with
+/// `memory` unlocked in between
+pub fn with_swap_io<R>(
+    memory: &Mutex<UserSpace>,
+    mut f: impl FnMut(&mut UserSpace) -> Result<R, UserPageFaultError>,
+) -> Result<R, UserPageFaultError> {
+    loop {
+        match f(&mut memory.lock()) {
+            Err(UserPageFaultError::SwapIo) => {
+                swap::run_io().map_err(|_| UserPageFaultError::Io)?;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Check that a text page of `image` mapped writable into another space is
+/// copied first, leaving the image cache and the original mapping intact
+pub fn image_test(image: &'static [u8]) {
+    let (src_pid, dst_pid) = (alloc_pid(), alloc_pid());
+    let src = Mutex::new(UserSpace::new(src_pid.pid()));
+    let entry = VirtAddr(src.lock().map_elf(image).unwrap().entry);
+    let vpn = entry.floor_page();
+    with_swap_io(&src, |space| space.fault_in(entry, UserPageFaultType::Read)).unwrap();
+    let mut linked = alloc::vec![0; PAGE_SIZE];
+    let cached = {
+        let space = src.lock();
+        let (image, index, _) = space.vma(vpn).unwrap().image_page(vpn).unwrap();
+        image.elf().fill_page(index * PAGE_SIZE, &mut linked);
+        image.shared_page(index).unwrap()
+    };
+    let frame = with_swap_io(&src, |space| space.find_frame(vpn)).unwrap();
+    let dst = Mutex::new(UserSpace::new(dst_pid.pid()));
+    let perm = UserAreaPerm::R | UserAreaPerm::W;
+    dst.lock().map(vpn, frame.clone(), perm).unwrap();
+    // As the other space would through its mapping
+    unsafe { PhysAddr::from(frame.ppn).as_mut_page_slice() }.fill(0);
+    let page = |frame: &FrameTracker| unsafe { PhysAddr::from(frame.ppn).as_page_slice() };
+    assert_eq!(page(&cached), &linked[..]);
+    let space = src.lock();
+    assert_eq!(
+        page(space.vma(vpn).unwrap().frame(vpn).unwrap()),
+        &linked[..]
+    );
+}
+
+/// Check that the relocations of a position-independent image linked above
+/// the place it is loaded at, which makes the load bias negative, are applied
+pub fn pie_test() {
+    const LINK: usize = 0x0ff0_0000;
+    const ENTRY: usize = LINK + 0x100;
+    const DYNAMIC: usize = PAGE_SIZE + 0x100;
+    const TARGET: usize = LINK + PAGE_SIZE;
+    const ADDEND: usize = LINK + 0x123;
+    let words = Box::leak(alloc::vec![0u64; 2 * PAGE_SIZE / 8].into_boxed_slice());
+    // SAFETY: the words are leaked, so the image lives for good
+    let image: &'static mut [u8] =
+        unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr().cast(), 2 * PAGE_SIZE) };
+    let mut put = |offset: usize, bytes: &[u8]| {
+        image[offset..][..bytes.len()].copy_from_slice(bytes);
+    };
+    // 64-bit little-endian ET_DYN for RISC-V, with three program headers
+    put(0, &[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+    put(0x10, &3u16.to_le_bytes());
+    put(0x12, &243u16.to_le_bytes());
+    put(0x14, &1u32.to_le_bytes());
+    put(0x18, &(ENTRY as u64).to_le_bytes());
+    put(0x20, &0x40u64.to_le_bytes());
+    put(0x34, &64u16.to_le_bytes());
+    put(0x36, &56u16.to_le_bytes());
+    put(0x38, &3u16.to_le_bytes());
+    // Text, data and the dynamic section at the start of the data
+    let headers = [
+        (1u32, 5u32, 0, LINK, PAGE_SIZE, PAGE_SIZE),
+        (1, 6, PAGE_SIZE, LINK + PAGE_SIZE, PAGE_SIZE, PAGE_SIZE),
+        (2, 6, DYNAMIC, LINK + DYNAMIC, 0x40, 8),
+    ];
+    for (i, (ty, flags, offset, vaddr, size, align)) in headers.into_iter().enumerate() {
+        let header = 0x40 + i * 56;
+        put(header, &ty.to_le_bytes());
+        put(header + 4, &flags.to_le_bytes());
+        for (j, value) in [offset, vaddr, vaddr, size, size, align]
+            .into_iter()
+            .enumerate()
+        {
+            put(header + 8 + j * 8, &(value as u64).to_le_bytes());
+        }
+    }
+    // DT_RELA, DT_RELASZ, DT_RELAENT and DT_NULL, then one R_RISCV_RELATIVE
+    let dynamic = [7, LINK + DYNAMIC + 0x40, 8, 24, 9, 24, 0, 0];
+    let rela = [TARGET, 3, ADDEND];
+    for (i, value) in dynamic.into_iter().chain(rela).enumerate() {
+        put(DYNAMIC + i * 8, &(value as u64).to_le_bytes());
+    }
+    let image: &'static [u8] = image;
+
+    // Almost every slot is below the link address, retry if one above is
+    // picked
+    loop {
+        let pid = alloc_pid();
+        let space = Mutex::new(UserSpace::new(pid.pid()));
+        let bias = space
+            .lock()
+            .map_elf(image)
+            .unwrap()
+            .entry
+            .wrapping_sub(ENTRY);
+        if (bias as isize) >= 0 {
+            continue;
+        }
+        let target = VirtAddr(TARGET.wrapping_add(bias));
+        with_swap_io(&space, |space| {
+            space.fault_in(target, UserPageFaultType::Read)
+        })
+        .unwrap();
+        let space = space.lock();
+        let vpn = target.floor_page();
+        let frame = space.vma(vpn).unwrap().frame(vpn).unwrap();
+        let page = unsafe { PhysAddr::from(frame.ppn).as_page_slice() };
+        assert_eq!(page[..8], ADDEND.wrapping_add(bias).to_le_bytes());
+        break;
+    }
+}
+
 bitflags! {
-    #[derive(Debug, Copy, Clone)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     pub struct UserAreaPerm: usize {
         const R = 1 << 0;
         const W = 1 << 1;
@@ -249,109 +1137,223 @@ impl UserAreaPerm {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum UserAreaType {
-    Framed,
+/// What a VMA maps
+#[derive(Clone)]
+pub enum VmaBacking {
+    /// Zero-filled memory
+    Anonymous,
+    /// `file` from page `offset` on. Shared mappings write to the page
+    /// cache, private ones get a copy on the first write.
+    File {
+        file: Arc<File>,
+        offset: usize,
+        shared: bool,
+    },
+    /// Segments of an executable, loaded `bias` bytes from their link
+    /// address
+    Image { image: Arc<Image>, bias: usize },
+}
+
+bitflags! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct VmaFlags: u8 {
+        /// Part of the stack, which grows down on faults below it
+        const STACK = 1 << 0;
+        /// Registered by `set_brk`
+        const HEAP = 1 << 1;
+    }
 }
 
+/// A page of a VMA which has been populated
 #[derive(Clone)]
-pub struct UserArea {
-    _ty: UserAreaType,
+enum VmaPage {
+    Present(Arc<FrameTracker>),
+    /// Swapped out, the slot is kept in the page table entry
+    Swapped,
+}
+
+/// A range of pages with the same permissions and backing. Pages are
+/// populated on demand, so only those touched have an entry in `pages`.
+pub struct Vma {
+    start: VirtPageNum,
+    end: VirtPageNum,
     perm: UserAreaPerm,
-    frame: Option<Arc<FrameTracker>>,
-    vpn: VirtPageNum,
-    cow: bool,
+    backing: VmaBacking,
+    flags: VmaFlags,
+    pages: BTreeMap<VirtPageNum, VmaPage>,
 }
 
-impl UserArea {
-    fn new(ty: UserAreaType, perm: UserAreaPerm, vpn: VirtPageNum) -> Self {
+impl Vma {
+    fn new(
+        start: VirtPageNum,
+        end: VirtPageNum,
+        perm: UserAreaPerm,
+        backing: VmaBacking,
+        flags: VmaFlags,
+    ) -> Self {
         Self {
-            _ty: ty,
+            start,
+            end,
             perm,
-            frame: None,
-            vpn,
-            cow: false,
+            backing,
+            flags,
+            pages: BTreeMap::new(),
         }
     }
 
-    fn new_with_frame(
-        ty: UserAreaType,
-        perm: UserAreaPerm,
-        vpn: VirtPageNum,
-        frame: Arc<FrameTracker>,
-    ) -> Self {
-        Self {
-            _ty: ty,
-            perm,
-            frame: Some(frame),
-            vpn,
-            cow: false,
+    fn contains(&self, vpn: VirtPageNum) -> bool {
+        (self.start..self.end).contains(&vpn)
+    }
+
+    /// File, page index in it and whether the mapping is shared for `vpn`
+    fn file_page(&self, vpn: VirtPageNum) -> Option<(&Arc<File>, usize, bool)> {
+        match &self.backing {
+            VmaBacking::Anonymous | VmaBacking::Image { .. } => None,
+            VmaBacking::File {
+                file,
+                offset,
+                shared,
+            } => Some((file, offset + (vpn.0 - self.start.0), *shared)),
         }
     }
 
-    fn perm(&self) -> UserAreaPerm {
-        self.perm
+    /// Image, page index in it as linked and load bias for `vpn`
+    fn image_page(&self, vpn: VirtPageNum) -> Option<(&Arc<Image>, usize, usize)> {
+        match &self.backing {
+            VmaBacking::Image { image, bias } => {
+                // The bias is negative for an image linked above its load
+                // address, so it is taken off the address, not the page number
+                let index = VirtAddr::from(vpn).0.wrapping_sub(*bias) / PAGE_SIZE;
+                image
+                    .elf()
+                    .pages()
+                    .contains(&index)
+                    .then_some((image, index, *bias))
+            }
+            _ => None,
+        }
     }
 
-    fn map(&mut self, page_table: &mut PageTable) -> Result<(), OsError> {
-        trace!("mapping user area: {:x?}, perm: {:?}", self.vpn, self.perm);
-        if self.frame.is_none() {
-            let frame = frame::alloc()?;
-            self.frame = Some(Arc::new(frame));
+    fn frame(&self, vpn: VirtPageNum) -> Option<&Arc<FrameTracker>> {
+        match self.pages.get(&vpn) {
+            Some(VmaPage::Present(frame)) => Some(frame),
+            _ => None,
         }
-        self.cow = false;
-        // TODO When type is not framed (file mapping)
-        page_table.map(
-            self.vpn,
-            self.frame.as_ref().unwrap().ppn,
-            self.perm.as_pte_flag(),
-        );
-        Ok(())
     }
 
-    fn map_with_frame_cow(
+    /// Whether `next`, which starts at the end of this VMA, could be part of it
+    fn can_merge(&self, next: &Vma) -> bool {
+        matches!(
+            (&self.backing, &next.backing),
+            (VmaBacking::Anonymous, VmaBacking::Anonymous)
+        ) && self.perm == next.perm
+            && self.flags == next.flags
+    }
+
+    fn append(&mut self, mut next: Vma) {
+        debug_assert!(self.end == next.start);
+        self.end = next.end;
+        self.pages.append(&mut next.pages);
+    }
+
+    /// Split off the pages from `at` on into a new VMA
+    fn split_off(&mut self, at: VirtPageNum) -> Vma {
+        debug_assert!(self.start < at && at < self.end);
+        let backing = match &self.backing {
+            VmaBacking::Anonymous => VmaBacking::Anonymous,
+            VmaBacking::Image { image, bias } => VmaBacking::Image {
+                image: image.clone(),
+                bias: *bias,
+            },
+            VmaBacking::File {
+                file,
+                offset,
+                shared,
+            } => VmaBacking::File {
+                file: file.clone(),
+                offset: offset + (at.0 - self.start.0),
+                shared: *shared,
+            },
+        };
+        let rest = Vma {
+            start: at,
+            end: self.end,
+            perm: self.perm,
+            backing,
+            flags: self.flags,
+            pages: self.pages.split_off(&at),
+        };
+        self.end = at;
+        rest
+    }
+
+    fn map(
         &mut self,
         page_table: &mut PageTable,
+        pid: Pid,
+        vpn: VirtPageNum,
         frame: Arc<FrameTracker>,
-    ) -> Result<(), OsError> {
-        trace!(
-            "cow mapping user area: {:x?}, perm: {:?}",
-            self.vpn, self.perm
-        );
-        self.frame = Some(frame);
-        self.cow = true;
-        page_table.map(
-            self.vpn,
-            self.frame.as_ref().unwrap().ppn,
-            self.perm.as_pte_flag() | PteFlags::COW & !PteFlags::W,
-        );
-        Ok(())
-    }
-
-    fn unmap(&mut self, page_table: &mut PageTable) {
-        if self.frame.is_some() {
-            page_table.unmap(self.vpn);
-            self.frame = None;
+    ) {
+        trace!("mapping user page: {:x?}, perm: {:?}", vpn, self.perm);
+        let page = frame.page();
+        page.add_rmap(pid, vpn);
+        if self.perm.contains(UserAreaPerm::W) {
+            page.clear_flags(PageFlags::ZEROED);
         }
+        page_table.map(vpn, frame.ppn, self.perm.as_pte_flag());
+        self.pages.insert(vpn, VmaPage::Present(frame));
     }
 
-    fn copy_data(&self, page_table: &mut PageTable, data: &[u8]) {
-        unsafe {
-            let dst = page_table
-                .query(self.vpn)
-                .unwrap()
-                .pa()
-                .as_mut_page_slice()
-                .as_mut_ptr();
-            core::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+    fn map_cow(
+        &mut self,
+        page_table: &mut PageTable,
+        pid: Pid,
+        vpn: VirtPageNum,
+        frame: Arc<FrameTracker>,
+    ) {
+        trace!("cow mapping user page: {:x?}, perm: {:?}", vpn, self.perm);
+        if !frame::is_zero_frame(frame.ppn) {
+            frame.page().add_rmap(pid, vpn);
         }
+        page_table.map(
+            vpn,
+            frame.ppn,
+            (self.perm.as_pte_flag() | PteFlags::COW) & !PteFlags::W,
+        );
+        self.pages.insert(vpn, VmaPage::Present(frame));
     }
 
-    fn is_mapped(&self) -> bool {
-        self.frame.is_some()
+    /// Returns the frame which was mapped at `vpn`, the caller should keep it
+    /// alive until stale translations are flushed.
+    fn unmap(
+        &mut self,
+        page_table: &mut PageTable,
+        pid: Pid,
+        vpn: VirtPageNum,
+    ) -> Option<Arc<FrameTracker>> {
+        let Some(VmaPage::Present(frame)) = self.pages.remove(&vpn) else {
+            return None;
+        };
+        page_table.unmap(vpn);
+        if !frame::is_zero_frame(frame.ppn) {
+            frame.page().remove_rmap(pid, vpn);
+        }
+        Some(frame)
     }
 
-    fn get_frame(&self) -> Arc<FrameTracker> {
-        self.frame.clone().unwrap()
+    /// Replace the mapping of `vpn` by swap `slot`, returning the frame
+    fn swap_out(
+        &mut self,
+        page_table: &mut PageTable,
+        pid: Pid,
+        vpn: VirtPageNum,
+        slot: usize,
+    ) -> Arc<FrameTracker> {
+        let Some(VmaPage::Present(frame)) = self.pages.insert(vpn, VmaPage::Swapped) else {
+            panic!("swapping out a page which is not present");
+        };
+        page_table.set_swap(vpn, slot);
+        frame.page().remove_rmap(pid, vpn);
+        frame
     }
 }
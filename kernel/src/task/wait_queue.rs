@@ -0,0 +1,43 @@
+//! Tasks sleeping until an event, woken by whoever raises it
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use crate::Mutex;
+
+use super::{
+    schedule::SCHEDULER,
+    taskdef::{TaskControlBlock, TaskStatus},
+};
+
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<Arc<TaskControlBlock>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Put `task` to sleep on the queue unless `ready` holds, returns
+    /// whether it did. `ready` is checked with the queue locked, so an event
+    /// raised meanwhile wakes the task instead of being missed.
+    pub fn wait_unless(&self, task: &Arc<TaskControlBlock>, ready: impl FnOnce() -> bool) -> bool {
+        let mut waiters = self.waiters.lock();
+        if ready() {
+            return false;
+        }
+        task.set_status(TaskStatus::Sleeping);
+        task.set_yield_flag(true);
+        waiters.push_back(task.clone());
+        true
+    }
+
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for task in waiters {
+            SCHEDULER.wake(task);
+        }
+    }
+}
@@ -6,7 +6,7 @@ use arch::tp;
 use log::info;
 use riscv::register::{sie, time};
 
-use self::consts::{CLOCK_FREQ, INTERRUPT_PER_SEC};
+use self::consts::{CLOCK_FREQ, INTERRUPT_PER_SEC, MSEC_PER_SEC};
 
 // TODO: this is hart-local
 // static mut TICKS: usize = 0;
@@ -39,6 +39,11 @@ pub fn sleep(duration: usize) {
     while time::read() < end {}
 }
 
+/// Milliseconds since boot
+pub fn get_time_ms() -> usize {
+    time::read() / (CLOCK_FREQ / MSEC_PER_SEC)
+}
+
 // pub fn get_ticks() -> usize {
 //     unsafe { TICKS }
 // }
@@ -19,27 +19,27 @@ impl Debug for UserContext {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "UserContext {{")?;
         writeln!(f, "  uregs: [")?;
-        for i in 0..32 {
+        for (i, reg) in self.uregs.iter().enumerate() {
             if i % 4 == 0 {
                 if i != 0 {
                     writeln!(f)?;
                 }
                 write!(f, "    ")?;
             }
-            write!(f, "x{:<2}: {:#18x}, ", i, self.uregs[i])?;
+            write!(f, "x{:<2}: {:#18x}, ", i, reg)?;
         }
         writeln!(f, "],")?;
         writeln!(f, "  usstatus: {:#x},", self.usstatus)?;
         writeln!(f, "  sepc: {:#x},", self.sepc)?;
         writeln!(f, "  ksregs: [")?;
-        for i in 0..12 {
+        for (i, reg) in self.ksregs.iter().enumerate() {
             if i % 4 == 0 {
                 if i != 0 {
                     writeln!(f)?;
                 }
                 write!(f, "    ")?;
             }
-            write!(f, "s{:<2}: {:#18x}, ", i, self.ksregs[i])?;
+            write!(f, "s{:<2}: {:#18x}, ", i, reg)?;
         }
         writeln!(f, "],")?;
         writeln!(f, "  kra: {:#x},", self.kra)?;
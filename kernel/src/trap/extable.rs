@@ -0,0 +1,30 @@
+//! Instructions allowed to fault in the kernel, with the address to resume
+//! at instead. Entries are emitted into the `__ex_table` section next to the
+//! instruction, see `mm::uaccess`.
+
+unsafe extern "C" {
+    static __ex_table_start: ExceptionTableEntry;
+    static __ex_table_end: ExceptionTableEntry;
+}
+
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+fn entries() -> &'static [ExceptionTableEntry] {
+    unsafe {
+        let start = &raw const __ex_table_start;
+        let end = &raw const __ex_table_end;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Where to continue after a fault at `sepc`, if the instruction has a fixup
+pub fn search(sepc: usize) -> Option<usize> {
+    entries()
+        .iter()
+        .find(|entry| entry.insn == sepc)
+        .map(|entry| entry.fixup)
+}
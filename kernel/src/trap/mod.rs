@@ -1,15 +1,23 @@
 use core::{
-    arch::{asm, global_asm, naked_asm},
+    arch::{global_asm, naked_asm},
     hint::unreachable_unchecked,
 };
 
-use crate::timer;
+use crate::{
+    config::{CPU_NUM, KERNEL_STACK_SIZE},
+    drivers,
+    mm::{address_space::K_STACK_BEG, consts::HUGE_PAGE_SIZE_BITS, kstack::SLOT_SIZE},
+    timer,
+};
+use oops::KernelTrapFrame;
 use riscv::{
     interrupt::{Trap, supervisor::Exception, supervisor::Interrupt},
     register::stvec::{self, TrapMode},
 };
 
 pub mod context;
+pub mod extable;
+pub mod oops;
 
 global_asm!(include_str!("trap.S"));
 
@@ -20,7 +28,7 @@ pub unsafe extern "C" fn stvec_table() {
     unsafe {
         naked_asm!(
             "
-             0: j {exception_handler}
+             0: j {kernel_exception_entry}
              1: j {ssoft_handler} #ssi
              2: j {default_interrupt_handler}
              3: j {default_interrupt_handler} 
@@ -29,7 +37,7 @@ pub unsafe extern "C" fn stvec_table() {
              6: j {default_interrupt_handler}
              7: j {default_interrupt_handler}
              8: j {default_interrupt_handler}
-             9: j {default_interrupt_handler} # sei
+             9: j {sei_handler} # sei
              10: j {default_interrupt_handler}
              11: j {default_interrupt_handler} 
              12: j {default_interrupt_handler}
@@ -37,32 +45,180 @@ pub unsafe extern "C" fn stvec_table() {
              14: j {default_interrupt_handler}
              15: j {default_interrupt_handler}
             ",
-            exception_handler = sym exception_handler,
+            kernel_exception_entry = sym kernel_exception_entry,
             ssoft_handler = sym ssoft_handler,
             default_interrupt_handler = sym default_interrupt_handler,
             timer_handler = sym timer_handler,
+            sei_handler = sym sei_handler,
         )
     }
 }
 
-// This shouldn't use interrupt abi but it works for now
-pub extern "riscv-interrupt-s" fn exception_handler() {
+const EMERGENCY_STACK_SIZE: usize = 1 << 16;
+
+#[repr(C, align(16))]
+struct EmergencyStack([u8; EMERGENCY_STACK_SIZE]);
+
+// Used to report a kernel stack overflow, the faulting stack is unusable
+#[unsafe(link_section = ".bss.stack")]
+static mut EMERGENCY_STACK: core::mem::MaybeUninit<[EmergencyStack; CPU_NUM]> =
+    core::mem::MaybeUninit::uninit();
+
+/// Exceptions taken in the kernel come here first. If the stack pointer is in
+/// the guard of a kernel stack, the handler could not even save registers, so
+/// switch to the emergency stack of the hart and report the overflow.
+/// sscratch is free while in the kernel and holds t0 meanwhile.
+#[unsafe(naked)]
+unsafe extern "C" fn kernel_exception_entry() {
+    naked_asm!(
+        "   csrw sscratch, t0
+            srai t0, sp, {region_shift}
+            addi t0, t0, {region}
+            bnez t0, 1f
+            slli t0, sp, 64 - {slot_bits}
+            srli t0, t0, 64 - {slot_bits} + {guard_bits}
+            beqz t0, 2f
+        1:  csrr t0, sscratch
+            j {kernel_trap}
+        2:  mv a0, sp
+            la sp, {emergency_stack}
+            addi t0, tp, 1
+            slli t0, t0, {emergency_bits}
+            add sp, sp, t0
+            call {kernel_stack_overflow}
+        ",
+        region_shift = const HUGE_PAGE_SIZE_BITS,
+        region = const -((K_STACK_BEG as isize) >> HUGE_PAGE_SIZE_BITS),
+        slot_bits = const SLOT_SIZE.trailing_zeros(),
+        guard_bits = const KERNEL_STACK_SIZE.trailing_zeros(),
+        kernel_trap = sym kernel_trap,
+        emergency_stack = sym EMERGENCY_STACK,
+        emergency_bits = const EMERGENCY_STACK_SIZE.trailing_zeros(),
+        kernel_stack_overflow = sym kernel_stack_overflow,
+    )
+}
+
+extern "C" fn kernel_stack_overflow(sp: usize) -> ! {
+    panic!(
+        "kernel stack overflow on hart {} (sp: {:#x}, stval: {:#x})",
+        arch::tp(),
+        sp,
+        riscv::register::stval::read()
+    )
+}
+
+/// Save every register into a `KernelTrapFrame` on the stack for
+/// `kernel_exception`, and return to where the frame says afterwards
+#[unsafe(naked)]
+unsafe extern "C" fn kernel_trap() {
+    naked_asm!(
+        "   addi sp, sp, -{frame_size}
+            sd x1, 1*8(sp)
+            sd x3, 3*8(sp)
+            sd x4, 4*8(sp)
+            sd x5, 5*8(sp)
+            sd x6, 6*8(sp)
+            sd x7, 7*8(sp)
+            sd x8, 8*8(sp)
+            sd x9, 9*8(sp)
+            sd x10, 10*8(sp)
+            sd x11, 11*8(sp)
+            sd x12, 12*8(sp)
+            sd x13, 13*8(sp)
+            sd x14, 14*8(sp)
+            sd x15, 15*8(sp)
+            sd x16, 16*8(sp)
+            sd x17, 17*8(sp)
+            sd x18, 18*8(sp)
+            sd x19, 19*8(sp)
+            sd x20, 20*8(sp)
+            sd x21, 21*8(sp)
+            sd x22, 22*8(sp)
+            sd x23, 23*8(sp)
+            sd x24, 24*8(sp)
+            sd x25, 25*8(sp)
+            sd x26, 26*8(sp)
+            sd x27, 27*8(sp)
+            sd x28, 28*8(sp)
+            sd x29, 29*8(sp)
+            sd x30, 30*8(sp)
+            sd x31, 31*8(sp)
+            addi t0, sp, {frame_size}
+            sd t0, 2*8(sp)
+            csrr t0, sepc
+            sd t0, 32*8(sp)
+            csrr t0, sstatus
+            sd t0, 33*8(sp)
+            mv a0, sp
+            call {kernel_exception}
+            ld t0, 32*8(sp)
+            csrw sepc, t0
+            ld t0, 33*8(sp)
+            csrw sstatus, t0
+            ld x1, 1*8(sp)
+            ld x3, 3*8(sp)
+            ld x4, 4*8(sp)
+            ld x5, 5*8(sp)
+            ld x6, 6*8(sp)
+            ld x7, 7*8(sp)
+            ld x8, 8*8(sp)
+            ld x9, 9*8(sp)
+            ld x10, 10*8(sp)
+            ld x11, 11*8(sp)
+            ld x12, 12*8(sp)
+            ld x13, 13*8(sp)
+            ld x14, 14*8(sp)
+            ld x15, 15*8(sp)
+            ld x16, 16*8(sp)
+            ld x17, 17*8(sp)
+            ld x18, 18*8(sp)
+            ld x19, 19*8(sp)
+            ld x20, 20*8(sp)
+            ld x21, 21*8(sp)
+            ld x22, 22*8(sp)
+            ld x23, 23*8(sp)
+            ld x24, 24*8(sp)
+            ld x25, 25*8(sp)
+            ld x26, 26*8(sp)
+            ld x27, 27*8(sp)
+            ld x28, 28*8(sp)
+            ld x29, 29*8(sp)
+            ld x30, 30*8(sp)
+            ld x31, 31*8(sp)
+            ld sp, 2*8(sp)
+            sret
+        ",
+        frame_size = const size_of::<KernelTrapFrame>(),
+        kernel_exception = sym kernel_exception,
+    )
+}
+
+/// Length of the instruction at `pc`, compressed ones take 2 bytes
+fn instruction_len(pc: usize) -> usize {
+    if unsafe { (pc as *const u16).read() } & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+extern "C" fn kernel_exception(frame: &mut KernelTrapFrame) {
+    let stval = riscv::register::stval::read();
     match riscv::register::scause::read()
         .cause()
         .try_into::<Interrupt, Exception>()
         .unwrap()
     {
         Trap::Exception(e) => match e {
-            Exception::Breakpoint => unsafe {
-                asm!(
-                    "
-                    csrr t0, sepc
-                    addi t0, t0, 2
-                    csrw sepc, t0
-                "
-                )
+            Exception::Breakpoint => frame.sepc += instruction_len(frame.sepc),
+            Exception::LoadPageFault
+            | Exception::StorePageFault
+            | Exception::LoadFault
+            | Exception::StoreFault => match extable::search(frame.sepc) {
+                Some(fixup) => frame.sepc = fixup,
+                None => oops::oops(frame, e, stval),
             },
-            _ => panic!("unhandled exception: {:?}", e),
+            _ => oops::oops(frame, e, stval),
         },
         Trap::Interrupt(_i) => unsafe { unreachable_unchecked() },
     }
@@ -104,7 +260,12 @@ pub extern "riscv-interrupt-s" fn timer_handler() {
     timer::tick();
 }
 
+pub extern "riscv-interrupt-s" fn sei_handler() {
+    drivers::plic::handle();
+}
+
 pub fn init() {
+    drivers::plic::init_hart();
     unsafe {
         set_kernel_trap();
         riscv::register::sstatus::set_sie();
@@ -116,7 +277,7 @@ pub fn init() {
 #[inline(always)]
 pub unsafe fn set_kernel_trap() {
     unsafe {
-        stvec::write(stvec_table as usize, TrapMode::Vectored);
+        stvec::write(stvec_table as *const () as usize, TrapMode::Vectored);
     }
 }
 
@@ -126,6 +287,6 @@ pub unsafe fn set_user_trap() {
         fn _user_to_kernel_trap();
     }
     unsafe {
-        stvec::write(_user_to_kernel_trap as usize, TrapMode::Direct);
+        stvec::write(_user_to_kernel_trap as *const () as usize, TrapMode::Direct);
     }
 }
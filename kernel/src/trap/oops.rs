@@ -0,0 +1,176 @@
+//! Reports of exceptions the kernel cannot recover from. One taken while
+//! acting for a task, inside `catch`, kills that task and resumes after
+//! `catch`, unless the hart holds a lock or runs with interrupts disabled.
+//! Anything else takes the whole kernel down.
+
+use core::{
+    arch::global_asm,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use arch::tp;
+use log::error;
+use riscv::interrupt::supervisor::Exception;
+use sync::depth;
+
+use crate::{config::CPU_NUM, panic::backtrace_from, task::hart::get_current_task};
+
+/// Registers of a trap taken in the kernel, `regs[0]` is unused
+#[repr(C)]
+pub struct KernelTrapFrame {
+    pub regs: [usize; 32],
+    pub sepc: usize,
+    pub sstatus: usize,
+}
+
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+impl fmt::Display for KernelTrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            " sepc: {:#018x} sstatus: {:#018x}",
+            self.sepc, self.sstatus
+        )?;
+        for (i, (name, value)) in REG_NAMES.iter().zip(self.regs).enumerate().skip(1) {
+            write!(f, " {:>4}: {:#018x}", name, value)?;
+            if i % 4 == 3 {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+const _: () = assert!(CPU_NUM <= depth::MAX_HARTS);
+
+/// Stack pointer to resume at on an oops, per hart, 0 outside of `catch`
+static RECOVERY_SP: [AtomicUsize; CPU_NUM] = [const { AtomicUsize::new(0) }; CPU_NUM];
+
+global_asm!(
+    "
+    .section .text
+    .p2align 2
+    .globl __oops_catch
+// Call a1 with a0, returns 0 when it returns or 1 when it oopses. The callee
+// saved registers and the previous recovery stack pointer are kept on the
+// stack, whose address becomes the recovery stack pointer.
+__oops_catch:
+    addi sp, sp, -112
+    sd ra, 0(sp)
+    sd s0, 8(sp)
+    sd s1, 16(sp)
+    sd s2, 24(sp)
+    sd s3, 32(sp)
+    sd s4, 40(sp)
+    sd s5, 48(sp)
+    sd s6, 56(sp)
+    sd s7, 64(sp)
+    sd s8, 72(sp)
+    sd s9, 80(sp)
+    sd s10, 88(sp)
+    sd s11, 96(sp)
+    la t0, {recovery}
+    slli t1, tp, 3
+    add t0, t0, t1
+    ld t1, 0(t0)
+    sd t1, 104(sp)
+    sd sp, 0(t0)
+    jalr a1
+    li a0, 0
+0:  la t0, {recovery}
+    slli t1, tp, 3
+    add t0, t0, t1
+    ld t1, 104(sp)
+    sd t1, 0(t0)
+    ld ra, 0(sp)
+    ld s0, 8(sp)
+    ld s1, 16(sp)
+    ld s2, 24(sp)
+    ld s3, 32(sp)
+    ld s4, 40(sp)
+    ld s5, 48(sp)
+    ld s6, 56(sp)
+    ld s7, 64(sp)
+    ld s8, 72(sp)
+    ld s9, 80(sp)
+    ld s10, 88(sp)
+    ld s11, 96(sp)
+    addi sp, sp, 112
+    ret
+
+    .globl __oops_recover
+// Returned to from the trap instead of the faulting code, with sp set to the
+// recovery stack pointer
+__oops_recover:
+    li a0, 1
+    j 0b
+    ",
+    recovery = sym RECOVERY_SP,
+);
+
+unsafe extern "C" {
+    fn __oops_catch(arg: *mut u8, f: extern "C" fn(*mut u8)) -> usize;
+    fn __oops_recover();
+}
+
+/// Run `f` on behalf of the current task. An oops in it kills the task and
+/// returns false, or panics if a lock is held. Nothing `f` owns is dropped,
+/// so it must be safe to abandon anywhere outside its critical sections.
+///
+/// The frames abandoned by an oops are not unwound, so whatever they own is
+/// leaked: heap buffers such as a `Vec` being filled, and reference counts
+/// such as the `Arc<TaskControlBlock>` of the current task `do_syscall`
+/// holds, which keeps the killed task and its address space allocated for
+/// good. An oops is a kernel bug, so each one costs that memory rather than
+/// taking the kernel down.
+pub fn catch(f: impl FnOnce()) -> bool {
+    extern "C" fn call<F: FnOnce()>(arg: *mut u8) {
+        let f = unsafe { &mut *(arg as *mut Option<F>) };
+        f.take().unwrap()()
+    }
+    fn call_for<F: FnOnce()>(_: &Option<F>) -> extern "C" fn(*mut u8) {
+        call::<F>
+    }
+    let mut f = Some(f);
+    let call = call_for(&f);
+    unsafe { __oops_catch(&mut f as *mut _ as *mut u8, call) == 0 }
+}
+
+/// Report the exception `cause` taken at `frame`, then kill the current task
+/// if inside `catch` and outside of any critical section, or panic otherwise
+pub fn oops(frame: &mut KernelTrapFrame, cause: Exception, stval: usize) {
+    let task = get_current_task();
+    // Counted before logging takes the console lock
+    let (locks, irqs_off) = (depth::locks_held(), depth::irqs_off());
+    let recovery = RECOVERY_SP[tp()].load(Ordering::Relaxed);
+    error!(
+        "\x1b[1;31mOops: {:?} on hart {} (stval: {:#x}) in task {:?}\n{}{}\x1b[1;0m",
+        cause,
+        tp(),
+        stval,
+        task.as_ref().map(|task| task.pid()),
+        frame,
+        backtrace_from(frame.sepc + size_of::<usize>(), frame.regs[8])
+    );
+    match task {
+        Some(task) if recovery != 0 && locks == 0 && irqs_off == 0 => {
+            error!("Killing task {:?}", task.pid());
+            task.exit();
+            frame.sepc = __oops_recover as *const () as usize;
+            frame.regs[2] = recovery;
+            // The fault may have been taken with user memory accessible
+            frame.sstatus &= !(1 << 18);
+        }
+        _ if recovery != 0 => panic!(
+            "fatal kernel exception: {:?} with {} locks held, interrupts disabled {} times",
+            cause, locks, irqs_off
+        ),
+        _ => panic!("fatal kernel exception: {:?}", cause),
+    }
+}
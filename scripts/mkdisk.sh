@@ -0,0 +1,34 @@
+#!/bin/sh
+# Create the disk image the kernel boots with: an MBR with a swap partition
+# (type 0x82) followed by a data partition, which tasks can open as "vda2".
+# Usage: mkdisk.sh <image> [size in MiB] [swap size in MiB]
+set -e
+
+image=$1
+size=${2:-128}
+swap=${3:-64}
+
+sectors_per_mib=2048
+first=$sectors_per_mib
+swap_sectors=$((swap * sectors_per_mib))
+data_first=$((first + swap_sectors))
+data_sectors=$((size * sectors_per_mib - data_first))
+
+# Little-endian 32-bit value as octal escapes for printf
+le32() {
+    printf '\\%03o\\%03o\\%03o\\%03o' \
+        $(($1 & 255)) $(($1 >> 8 & 255)) $(($1 >> 16 & 255)) $(($1 >> 24 & 255))
+}
+
+# Partition table entry: not bootable, no CHS addresses
+entry() {
+    printf "\\000\\000\\000\\000\\$(printf %03o "$1")\\000\\000\\000$(le32 "$2")$(le32 "$3")"
+}
+
+rm -f "$image"
+dd if=/dev/zero of="$image" bs=1048576 count=0 seek="$size" 2>/dev/null
+{
+    entry $((0x82)) "$first" "$swap_sectors"
+    entry $((0x83)) "$data_first" "$data_sectors"
+} | dd of="$image" bs=1 seek=446 conv=notrunc 2>/dev/null
+printf '\125\252' | dd of="$image" bs=1 seek=510 conv=notrunc 2>/dev/null
@@ -3,16 +3,15 @@ use core::cmp::max;
 use core::mem::size_of;
 use core::ptr::NonNull;
 
-use crate::consts::{HEAP_BEG, HEAP_SIZE, PAGE_SIZE};
-use crate::syscall::{syscall_mem_alloc, syscall_mem_unmap};
+use crate::consts::PAGE_SIZE;
+use crate::syscall::syscall_brk;
 
-use super::bitmap::Bitmap;
 use super::list;
 
 pub struct Heap<const N: usize> {
     free_area: [list::List; N],
-    heap_start: usize,
-    bitmap: Bitmap<{ HEAP_SIZE / PAGE_SIZE / usize::BITS as usize }>,
+    // Current program break, 0 until the heap is first grown
+    brk: usize,
     total: usize,
     allocated: usize,
 }
@@ -21,8 +20,7 @@ impl<const N: usize> Heap<N> {
     pub const fn new() -> Self {
         Heap {
             free_area: [list::List::new(); N],
-            heap_start: HEAP_BEG,
-            bitmap: Bitmap::new(),
+            brk: 0,
             total: 0,
             allocated: 0,
         }
@@ -46,51 +44,20 @@ impl<const N: usize> Heap<N> {
         self.total += total;
     }
 
-    unsafe fn add_size(&mut self, start: usize, size: usize) {
-        unsafe { self.add_range(start, start + size) };
-    }
-
-    fn add_page(&mut self, count: usize, align: usize) {
-        assert!(count.is_power_of_two() && align.is_power_of_two());
-        if let Some(start) = self.bitmap.find_contiguous(count, align) {
-            for i in start..start + count {
-                let page = self.heap_start + i * PAGE_SIZE;
-                match syscall_mem_alloc(0, page, 0x3) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        panic!("Failed to allocate memory: {:?}", e);
-                    }
-                }
-            }
-            // Mark the pages as allocated in the bitmap
-            self.bitmap.set(start, count);
-            self.total += count * PAGE_SIZE;
-            // Add the pages to the free area
-            unsafe {
-                self.add_size(
-                    self.heap_start + start * PAGE_SIZE,
-                    count * PAGE_SIZE,
-                );
-            }
-        }
-    }
-
-    fn add_page_1(&mut self) {
-        if let Some(next_zero) = self.bitmap.first_zero() {
-            let page = self.heap_start + next_zero * PAGE_SIZE;
-            match syscall_mem_alloc(0, page, 0x3) {
-                Ok(_) => {
-                    self.bitmap.set(next_zero, 1);
-                    self.total += PAGE_SIZE;
-                    unsafe { self.free_area[N - 1].push(page as *mut usize) }; // 11 is the order for 4kB pages
-                    return;
-                }
-                Err(e) => {
-                    panic!("Failed to allocate memory: {:?}", e);
-                }
-            }
+    /// Moves the program break up so that a free block of `size` bytes becomes available.
+    fn grow(&mut self, size: usize) -> Result<(), ()> {
+        if self.brk == 0 {
+            self.brk = syscall_brk(0).map_err(|_| ())?;
         }
-        panic!("User heap overflow");
+        let size = size.max(PAGE_SIZE);
+        // Blocks are aligned to their size, the gap below the new block
+        // goes to the smaller free lists.
+        let start = (self.brk + size - 1) & !(size - 1);
+        let end = start + size;
+        let old_brk = self.brk;
+        self.brk = syscall_brk(end).map_err(|_| ())?;
+        unsafe { self.add_range(old_brk, end) };
+        Ok(())
     }
 
     pub fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
@@ -127,8 +94,10 @@ impl<const N: usize> Heap<N> {
             }
         }
         // No free block found, ask the kernel for more memory
-        let page_count = size.div_ceil(PAGE_SIZE);
-        self.add_page(page_count, page_count.next_power_of_two());
+        if n >= N {
+            return Err(());
+        }
+        self.grow(size)?;
         // Try again
         self.alloc(layout)
     }
@@ -138,14 +107,12 @@ impl<const N: usize> Heap<N> {
             layout.size().next_power_of_two(),
             max(layout.align(), size_of::<usize>()),
         );
-        let order = size.trailing_zeros() as usize;
+        let mut order = size.trailing_zeros() as usize;
+        let mut block = ptr.as_ptr() as usize;
         unsafe {
-            self.free_area[order].push(ptr.as_ptr() as *mut usize);
+            self.free_area[order].push(block as *mut usize);
 
             // Check for free buddy
-            let mut order = order;
-            let mut block = ptr.as_ptr() as usize;
-
             while order < N - 1 {
                 let mut flag = false;
                 let buddy = block ^ (1 << order);
@@ -168,13 +135,14 @@ impl<const N: usize> Heap<N> {
         }
         self.allocated -= size;
 
-        // free several pages
-        while let Some(free_page) = self.free_area[N - 1].pop() {
-            let page = free_page as usize;
-            let index = (page - self.heap_start) / PAGE_SIZE;
-            syscall_mem_unmap(0, page).unwrap();
-            self.bitmap.clear(index, 1);
-            self.total -= PAGE_SIZE;
+        // Return the merged block to the kernel if it sits right below the break.
+        // It was pushed last, so it is at the head of its list.
+        if order >= PAGE_SIZE.trailing_zeros() as usize && block + (1 << order) == self.brk {
+            if let Ok(brk) = syscall_brk(block) {
+                self.free_area[order].pop();
+                self.total -= 1 << order;
+                self.brk = brk;
+            }
         }
     }
 
@@ -1,13 +1,12 @@
 use core::alloc::GlobalAlloc;
 
-use crate::consts::PAGE_SIZE;
+use crate::consts::HEAP_SIZE;
 
-mod bitmap;
 mod buddy;
 mod list;
 
 pub struct Allocator {
-    heap: sync::SpinMutex<buddy::Heap<20>>,
+    heap: sync::SpinMutex<buddy::Heap<{ HEAP_SIZE.trailing_zeros() as usize + 1 }>>,
 }
 
 impl Default for Allocator {
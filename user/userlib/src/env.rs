@@ -0,0 +1,154 @@
+//! Arguments, environment and auxiliary vector the kernel leaves on the
+//! initial stack: argc at the stack pointer, then the NULL-terminated argv and
+//! envp arrays and the auxiliary vector ended by `AT_NULL`.
+
+use core::{
+    ffi::CStr,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY: usize = 9;
+pub const AT_RANDOM: usize = 25;
+/// Number of harts, not a tag Linux defines
+pub const AT_HART_COUNT: usize = 0x1000;
+
+static INITIAL_SP: AtomicPtr<usize> = AtomicPtr::new(null_mut());
+
+/// Record the initial stack pointer, done by `_start` before `main` runs
+///
+/// # Safety
+///
+/// `sp` must point to the stack laid out by the kernel, which must stay
+/// untouched for the life of the process.
+pub unsafe fn init(sp: *mut usize) {
+    INITIAL_SP.store(sp, Ordering::Relaxed);
+}
+
+// argc, argv[0], ... as words from the initial stack pointer
+fn words() -> *const usize {
+    let sp = INITIAL_SP.load(Ordering::Relaxed);
+    assert!(!sp.is_null(), "env used before _start");
+    sp
+}
+
+/// Iterate over a NULL-terminated array of strings
+fn strings(mut ptr: *const usize) -> impl Iterator<Item = &'static str> {
+    core::iter::from_fn(move || {
+        let s = unsafe { *ptr } as *const core::ffi::c_char;
+        if s.is_null() {
+            return None;
+        }
+        ptr = unsafe { ptr.add(1) };
+        let s = unsafe { CStr::from_ptr(s) };
+        Some(s.to_str().unwrap_or(""))
+    })
+}
+
+fn argc() -> usize {
+    unsafe { *words() }
+}
+
+fn envp() -> *const usize {
+    unsafe { words().add(argc() + 2) }
+}
+
+/// The command line arguments, handed to `main` by `#[user_main]`
+#[derive(Debug, Clone, Copy)]
+pub struct Args {
+    argc: usize,
+}
+
+impl Args {
+    pub fn get() -> Self {
+        Args { argc: argc() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.argc
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.argc == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> {
+        strings(unsafe { words().add(1) })
+    }
+}
+
+/// The environment as `NAME=value` strings
+pub fn vars() -> impl Iterator<Item = &'static str> {
+    strings(envp())
+}
+
+/// The value of environment variable `name`
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find_map(|var| var.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// The value of auxiliary vector entry `ty`
+pub fn aux(ty: usize) -> Option<usize> {
+    let mut ptr = envp();
+    while unsafe { *ptr } != 0 {
+        ptr = unsafe { ptr.add(1) };
+    }
+    ptr = unsafe { ptr.add(1) };
+    loop {
+        let (entry, value) = unsafe { (*ptr, *ptr.add(1)) };
+        match entry {
+            AT_NULL => return None,
+            _ if entry == ty => return Some(value),
+            _ => ptr = unsafe { ptr.add(2) },
+        }
+    }
+}
+
+pub fn hart_count() -> usize {
+    aux(AT_HART_COUNT).unwrap_or(1)
+}
+
+/// 16 random bytes from the kernel, to seed generators with
+pub fn random_seed() -> [u8; 16] {
+    aux(AT_RANDOM).map_or([0; 16], |ptr| unsafe { *(ptr as *const [u8; 16]) })
+}
+
+/// What `main` may return, turned into the exit code of the process
+pub trait Termination {
+    fn report(self) -> usize;
+}
+
+impl Termination for () {
+    fn report(self) -> usize {
+        0
+    }
+}
+
+impl Termination for usize {
+    fn report(self) -> usize {
+        self
+    }
+}
+
+impl Termination for i32 {
+    fn report(self) -> usize {
+        self as usize
+    }
+}
+
+impl<E: core::fmt::Debug> Termination for Result<(), E> {
+    fn report(self) -> usize {
+        match self {
+            Ok(()) => 0,
+            Err(e) => {
+                crate::println!("Error: {:?}", e);
+                1
+            }
+        }
+    }
+}
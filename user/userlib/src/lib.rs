@@ -5,6 +5,7 @@ use syscall::syscall_panic;
 
 pub mod console;
 pub mod consts;
+pub mod env;
 pub mod error;
 pub mod syscall;
 
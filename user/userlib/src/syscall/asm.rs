@@ -88,3 +88,29 @@ pub fn syscall_5(id: SyscallId, a0: usize, a1: usize, a2: usize, a3: usize, a4:
     }
     ret
 }
+
+#[inline(always)]
+pub fn syscall_6(
+    id: SyscallId,
+    a0: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!("ecall",
+            in("a7") id as usize,
+            in("a0") a0,
+            in("a1") a1,
+            in("a2") a2,
+            in("a3") a3,
+            in("a4") a4,
+            in("a5") a5,
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
@@ -1,3 +1,4 @@
+#[allow(dead_code)]
 #[repr(usize)]
 pub enum SyscallId {
     SysPutchar,
@@ -18,4 +19,19 @@ pub enum SyscallId {
     SysCGetc,
     SysWriteDev,
     SysReadDev,
+    SysOpen,
+    SysClose,
+    SysRead,
+    SysWrite,
+    SysSeek,
+    SysFstat,
+    SysFsync,
+    SysFtruncate,
+    SysRemove,
+    SysBrk,
+    SysSysinfo,
+    SysMmap,
+    SysMsync,
+    SysExit,
+    SysStackLimit,
 }
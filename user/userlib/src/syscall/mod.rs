@@ -101,6 +101,13 @@ pub fn syscall_panic(msg: &str) -> ! {
     unsafe { unreachable_unchecked() }
 }
 
+/// End the calling process with `code`
+#[inline(always)]
+pub fn syscall_exit(code: usize) -> ! {
+    asm::syscall_1(SyscallId::SysExit, code);
+    unsafe { unreachable_unchecked() }
+}
+
 #[inline(always)]
 pub fn syscall_ipc_try_send(
     to_envid: usize,
@@ -160,3 +167,104 @@ pub fn syscall_read_dev(dev: usize, buf: &mut [u8]) -> Result<usize, ErrorCode>
         err => Err(ErrorCode::from(err)),
     }
 }
+
+#[inline(always)]
+pub fn syscall_brk(addr: usize) -> Result<usize, ErrorCode> {
+    match asm::syscall_1(SyscallId::SysBrk, addr) {
+        brk if brk >= 0 => Ok(brk as usize),
+        err => Err(ErrorCode::from(err)),
+    }
+}
+
+/// Grows or shrinks the heap by `increment` bytes, returning the previous break.
+#[inline(always)]
+pub fn syscall_sbrk(increment: isize) -> Result<usize, ErrorCode> {
+    let brk = syscall_brk(0)?;
+    if increment != 0 {
+        syscall_brk(brk.wrapping_add_signed(increment))?;
+    }
+    Ok(brk)
+}
+
+/// Sets the maximum size of the stack to `limit` bytes, or only queries it if
+/// `limit` is 0. Returns the limit in effect.
+#[inline(always)]
+pub fn syscall_stack_limit(limit: usize) -> Result<usize, ErrorCode> {
+    match asm::syscall_1(SyscallId::SysStackLimit, limit) {
+        limit if limit >= 0 => Ok(limit as usize),
+        err => Err(ErrorCode::from(err)),
+    }
+}
+
+/// Number of block orders reported by [`syscall_sysinfo`]
+pub const SYSINFO_ORDERS: usize = 32;
+
+/// Memory statistics of the system, all sizes are in pages.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SysInfo {
+    pub page_size: usize,
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub used_pages: usize,
+    /// Number of free blocks of `1 << order` pages
+    pub free_blocks: [usize; SYSINFO_ORDERS],
+}
+
+#[inline(always)]
+pub fn syscall_sysinfo() -> Result<SysInfo, ErrorCode> {
+    let mut info = core::mem::MaybeUninit::<SysInfo>::uninit();
+    match asm::syscall_1(SyscallId::SysSysinfo, info.as_mut_ptr() as usize) {
+        0 => Ok(unsafe { info.assume_init() }),
+        err => Err(ErrorCode::from(err)),
+    }
+}
+
+/// Writes to the mapping go to the file
+pub const MAP_SHARED: usize = 1;
+/// Writes to the mapping are private copies
+pub const MAP_PRIVATE: usize = 2;
+
+/// Opens the file called `name`, a partition of the disk such as `vda1`,
+/// returning its descriptor.
+#[inline(always)]
+pub fn syscall_open(name: &str) -> Result<usize, ErrorCode> {
+    match asm::syscall_2(SyscallId::SysOpen, name.as_ptr() as usize, name.len()) {
+        fd if fd >= 0 => Ok(fd as usize),
+        err => Err(ErrorCode::from(err)),
+    }
+}
+
+#[inline(always)]
+pub fn syscall_close(fd: usize) -> Result<(), ErrorCode> {
+    match asm::syscall_1(SyscallId::SysClose, fd) {
+        0 => Ok(()),
+        err => Err(ErrorCode::from(err)),
+    }
+}
+
+/// Maps `len` bytes of the open file `fd` starting at `offset` at `va`, or
+/// anywhere if `va` is 0, returning the address of the mapping.
+#[inline(always)]
+pub fn syscall_mmap(
+    va: usize,
+    len: usize,
+    perm: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> Result<usize, ErrorCode> {
+    match asm::syscall_6(SyscallId::SysMmap, va, len, perm, flags, fd, offset) {
+        va if va >= 0 => Ok(va as usize),
+        err => Err(ErrorCode::from(err)),
+    }
+}
+
+/// Writes the changes to shared mappings in `va..va + len` back to their files.
+#[inline(always)]
+pub fn syscall_msync(va: usize, len: usize) -> Result<(), ErrorCode> {
+    match asm::syscall_2(SyscallId::SysMsync, va, len) {
+        0 => Ok(()),
+        err => Err(ErrorCode::from(err)),
+    }
+}
@@ -2,10 +2,18 @@ extern crate proc_macro;
 use proc_macro::TokenStream;
 use quote::quote;
 
+/// Make the function the entry point of the program. It may take
+/// `userlib::env::Args`, and return anything implementing
+/// `userlib::env::Termination`, which becomes the exit code.
 #[proc_macro_attribute]
 pub fn user_main(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let input = syn::parse_macro_input!(item as syn::ItemFn);
     let main_fn = &input.sig.ident;
+    let call = if input.sig.inputs.is_empty() {
+        quote! { #main_fn() }
+    } else {
+        quote! { #main_fn(userlib::env::Args::get()) }
+    };
 
     let expanded = quote! {
         #[unsafe(naked)]
@@ -15,13 +23,20 @@ pub fn user_main(_attr: TokenStream, item: TokenStream) -> TokenStream {
             unsafe {
                 core::arch::naked_asm!(
                     "
-                    call {main}
+                    mv a0, sp
+                    call {start}
                     ",
-                    main = sym #main_fn,
+                    start = sym __user_start,
                 )
             }
         }
 
+        extern "C" fn __user_start(sp: *mut usize) -> ! {
+            unsafe { userlib::env::init(sp) };
+            let code = userlib::env::Termination::report(#call);
+            userlib::syscall::syscall_exit(code)
+        }
+
         #input
     };
 